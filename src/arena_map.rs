@@ -0,0 +1,1188 @@
+use crate::cursor::{inclusive_bounds, Cursor, Tree};
+use crate::key::PatriciaKey;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt::{self, Debug};
+use core::hash::{Hash, Hasher};
+use core::iter::FusedIterator;
+use core::mem;
+use core::ops::{Index, RangeBounds};
+use duplicate::{duplicate, duplicate_item};
+
+#[derive(Clone, Debug)]
+struct LeafNode<V, K> {
+    key: K,
+    value: V,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct InternalNode<K> {
+    key_prefix: K,
+    branch_bit: u8,
+    left: u32,
+    right: u32,
+}
+
+#[derive(Clone, Debug)]
+enum Node<V, K> {
+    Leaf(LeafNode<V, K>),
+    Internal(InternalNode<K>),
+    /// A slot that was freed by a removal and is waiting to be reused.
+    Vacant,
+}
+
+impl<V, K: PatriciaKey> Node<V, K> {
+    /// Returns the low bits shared by all keys below the node and how many bits that is.
+    fn prefix(&self) -> (K, u32) {
+        match self {
+            Node::Leaf(LeafNode { key, .. }) => (*key, K::BITS),
+            Node::Internal(InternalNode {
+                key_prefix,
+                branch_bit,
+                ..
+            }) => (*key_prefix, *branch_bit as u32),
+            Node::Vacant => unreachable!(),
+        }
+    }
+}
+
+/// A map with the same structure as [`PatriciaTreeMap`], but with all nodes stored in a single
+/// `Vec` and children referred to by their index in it. This avoids an allocation per node and
+/// keeps the nodes close together in memory.
+///
+/// It has the core map API of [`PatriciaTreeMap`]: lookups, insertion and removal, the entry
+/// API, iteration and ranges in ascending key order, and the first and last entries. The prefix
+/// queries, order statistics and bulk operations such as splitting, merging and draining are not
+/// implemented; collect the entries into a [`PatriciaTreeMap`] where those are needed.
+///
+/// [`PatriciaTreeMap`]: crate::map::PatriciaTreeMap
+#[derive(Clone)]
+pub struct PatriciaArenaMap<V, K = u64> {
+    size: usize,
+    root: Option<u32>,
+    nodes: Vec<Node<V, K>>,
+    /// Indices of the vacant slots in `nodes`.
+    free: Vec<u32>,
+}
+
+impl<V> PatriciaArenaMap<V> {
+    /// Creates an empty map with `u64` keys. Maps with other key types are created with
+    /// [`Default::default`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<V, K: PatriciaKey> PatriciaArenaMap<V, K> {
+    /// Creates an empty map with room for `n` entries, i.e. `2n - 1` nodes, before the node
+    /// storage has to grow.
+    pub fn with_capacity(n: usize) -> Self {
+        Self {
+            nodes: Vec::with_capacity((2 * n).saturating_sub(1)),
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.nodes.clear();
+        self.free.clear();
+        self.size = 0;
+    }
+
+    fn get_prefix(key: K, branch_bit: u8) -> K {
+        key.low_bits(branch_bit as u32)
+    }
+
+    fn is_left(key: K, branch_bit: u8) -> bool {
+        !key.bit(branch_bit as u32)
+    }
+
+    /// Stores `node` in a vacant slot, or a new one if there are none, and returns its index.
+    fn alloc(&mut self, node: Node<V, K>) -> u32 {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index as usize] = node;
+                index
+            }
+            None => {
+                let index = u32::try_from(self.nodes.len()).expect("too many nodes");
+                self.nodes.push(node);
+                index
+            }
+        }
+    }
+
+    /// Takes the node out of slot `index`, leaving it vacant.
+    fn dealloc(&mut self, index: u32) -> Node<V, K> {
+        self.free.push(index);
+        mem::replace(&mut self.nodes[index as usize], Node::Vacant)
+    }
+
+    fn find_insertion_point(&self, key: K) -> Option<u32> {
+        let mut index = self.root?;
+        loop {
+            match &self.nodes[index as usize] {
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    left,
+                    right,
+                }) if *key_prefix == Self::get_prefix(key, *branch_bit) => {
+                    index = if Self::is_left(key, *branch_bit) {
+                        *left
+                    } else {
+                        *right
+                    };
+                }
+                _ => return Some(index),
+            }
+        }
+    }
+
+    /// Returns the index of the leaf of `key`.
+    fn find_leaf(&self, key: K) -> Option<u32> {
+        let index = self.find_insertion_point(key)?;
+        match &self.nodes[index as usize] {
+            Node::Leaf(LeafNode { key: k, .. }) if *k == key => Some(index),
+            _ => None,
+        }
+    }
+
+    #[duplicate_item(
+      method     self_type    reference(type);
+      [leaf]     [&Self]      [&type];
+      [leaf_mut] [&mut Self]  [&mut type];
+    )]
+    #[allow(clippy::needless_arbitrary_self_type)]
+    fn method(self: self_type, index: u32) -> reference([LeafNode<V, K>]) {
+        match reference([self.nodes[index as usize]]) {
+            Node::Leaf(leaf) => leaf,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get(&self, key: K) -> Option<&V> {
+        Some(&self.leaf(self.find_leaf(key)?).value)
+    }
+
+    pub fn get_key_value(&self, key: K) -> Option<(K, &V)> {
+        Some((key, self.get(key)?))
+    }
+
+    pub fn get_mut(&mut self, key: K) -> Option<&mut V> {
+        let index = self.find_leaf(key)?;
+        Some(&mut self.leaf_mut(index).value)
+    }
+
+    /// Returns whether `key` is in the map.
+    pub fn contains_key(&self, key: K) -> bool {
+        self.find_leaf(key).is_some()
+    }
+
+    #[deprecated(note = "renamed to `contains_key`, like `BTreeMap::contains_key`")]
+    pub fn contains(&self, key: K) -> bool {
+        self.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.insert_leaf(key, value).1
+    }
+
+    /// Inserts `key` like [`insert`](Self::insert), and also returns the index of its leaf.
+    fn insert_leaf(&mut self, key: K, value: V) -> (u32, Option<V>) {
+        let index = match self.find_insertion_point(key) {
+            None => {
+                let leaf = self.alloc(Node::Leaf(LeafNode { key, value }));
+                self.root = Some(leaf);
+                self.size += 1;
+                return (leaf, None);
+            }
+            Some(index) => index,
+        };
+
+        let other = match &mut self.nodes[index as usize] {
+            Node::Leaf(LeafNode { key: k, value: v }) if *k == key => {
+                return (index, Some(mem::replace(v, value)));
+            }
+            Node::Leaf(LeafNode { key: k, .. }) => *k,
+            Node::Internal(InternalNode { key_prefix, .. }) => *key_prefix,
+            Node::Vacant => unreachable!(),
+        };
+        let branch_bit = other.branch_bit(key) as u8;
+
+        // The node moves to a new slot, and its old slot becomes the new internal node, so the
+        // index stored in its parent stays valid.
+        let leaf = self.alloc(Node::Leaf(LeafNode { key, value }));
+        let old_node = mem::replace(&mut self.nodes[index as usize], Node::Vacant);
+        let old_node = self.alloc(old_node);
+        let (left, right) = if Self::is_left(key, branch_bit) {
+            (leaf, old_node)
+        } else {
+            (old_node, leaf)
+        };
+        self.nodes[index as usize] = Node::Internal(InternalNode {
+            key_prefix: Self::get_prefix(key, branch_bit),
+            branch_bit,
+            left,
+            right,
+        });
+        self.size += 1;
+        (leaf, None)
+    }
+
+    pub fn remove(&mut self, key: K) -> Option<V> {
+        let mut parent = None;
+        let mut index = self.root?;
+        loop {
+            match &self.nodes[index as usize] {
+                Node::Leaf(LeafNode { key: k, .. }) if *k == key => break,
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    left,
+                    right,
+                }) if *key_prefix == Self::get_prefix(key, *branch_bit) => {
+                    parent = Some((index, *left, *right));
+                    index = if Self::is_left(key, *branch_bit) {
+                        *left
+                    } else {
+                        *right
+                    };
+                }
+                _ => return None,
+            }
+        }
+
+        // The sibling of the leaf moves into the slot of their parent.
+        match parent {
+            None => self.root = None,
+            Some((parent, left, right)) => {
+                let sibling = if index == left { right } else { left };
+                self.nodes[parent as usize] = self.dealloc(sibling);
+            }
+        }
+        self.size -= 1;
+        match self.dealloc(index) {
+            Node::Leaf(LeafNode { value, .. }) => Some(value),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Removes `key` from the map, returning the stored key and value if it was present.
+    pub fn remove_entry(&mut self, key: K) -> Option<(K, V)> {
+        self.remove(key).map(|value| (key, value))
+    }
+
+    /// Returns the entry of `key` in the map for in-place manipulation.
+    pub fn entry(&mut self, key: K) -> Entry<'_, V, K> {
+        match self.find_leaf(key) {
+            Some(index) => Entry::Occupied(OccupiedEntry { map: self, index }),
+            None => Entry::Vacant(VacantEntry { map: self, key }),
+        }
+    }
+
+    /// Returns the entry with the smallest (`first_entry`) or largest (`last_entry`) key, which
+    /// can be modified or removed in place.
+    #[duplicate_item(
+      method        find;
+      [first_entry] [min];
+      [last_entry]  [max];
+    )]
+    pub fn method(&mut self) -> Option<OccupiedEntry<'_, V, K>> {
+        let (key, _) = self.find()?;
+        let index = self.find_leaf(key).unwrap();
+        Some(OccupiedEntry { map: self, index })
+    }
+
+    /// Removes and returns the entry with the smallest key.
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        let (key, _) = self.min()?;
+        self.remove_entry(key)
+    }
+
+    /// Removes and returns the entry with the largest key.
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        let (key, _) = self.max()?;
+        self.remove_entry(key)
+    }
+
+    /// Retains only the entries for which `f` returns `true`, visiting the entries in the order
+    /// of their slots in the node storage rather than in key order.
+    pub fn retain<F: FnMut(K, &mut V) -> bool>(&mut self, mut f: F) {
+        let removed: Vec<K> = (self.nodes.iter_mut())
+            .filter_map(|node| match node {
+                Node::Leaf(LeafNode { key, value }) => (!f(*key, value)).then_some(*key),
+                _ => None,
+            })
+            .collect();
+        for key in removed {
+            self.remove(key);
+        }
+    }
+
+    /// Returns the entry with the smallest key.
+    pub fn min(&self) -> Option<(K, &V)> {
+        self.iter().next()
+    }
+
+    /// Returns the entry with the largest key.
+    pub fn max(&self) -> Option<(K, &V)> {
+        self.iter().next_back()
+    }
+
+    /// Returns the entry with the smallest key, like [`BTreeMap::first_key_value`].
+    pub fn first_key_value(&self) -> Option<(K, &V)> {
+        self.min()
+    }
+
+    /// Returns the entry with the largest key, like [`BTreeMap::last_key_value`].
+    pub fn last_key_value(&self) -> Option<(K, &V)> {
+        self.max()
+    }
+
+    /// Returns the entry with the smallest key `>= key`.
+    pub fn ceiling(&self, key: K) -> Option<(K, &V)> {
+        self.range(key..).next()
+    }
+
+    /// Returns the entry with the largest key `<= key`.
+    pub fn floor(&self, key: K) -> Option<(K, &V)> {
+        self.range(..=key).next_back()
+    }
+
+    /// Moves the nodes to the front of the node storage, dropping the vacant slots left by
+    /// removals, and releases the capacity that is no longer needed.
+    pub fn shrink_to_fit(&mut self) {
+        /// Moves the subtree at `index` from `nodes` to `compacted`, children first, and returns
+        /// the new index of its root.
+        fn aux<V, K>(nodes: &mut [Node<V, K>], index: u32, compacted: &mut Vec<Node<V, K>>) -> u32 {
+            let node = match mem::replace(&mut nodes[index as usize], Node::Vacant) {
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    left,
+                    right,
+                }) => Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    left: aux(nodes, left, compacted),
+                    right: aux(nodes, right, compacted),
+                }),
+                node => node,
+            };
+            compacted.push(node);
+            (compacted.len() - 1) as u32
+        }
+
+        let mut compacted = Vec::with_capacity(self.nodes.len() - self.free.len());
+        if let Some(root) = self.root {
+            self.root = Some(aux(&mut self.nodes, root, &mut compacted));
+        }
+        self.nodes = compacted;
+        self.free = Vec::new();
+    }
+
+    /// Returns the number of node slots in use, internal nodes and leaves alike. Vacant slots
+    /// waiting to be reused are not counted.
+    pub fn allocated_nodes(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    /// Returns the approximate number of heap bytes used by the map: the node storage and the
+    /// list of vacant slots, including their unused capacity. Memory owned by the values
+    /// themselves is not included.
+    pub fn memory_usage(&self) -> usize {
+        self.nodes.capacity() * mem::size_of::<Node<V, K>>()
+            + self.free.capacity() * mem::size_of::<u32>()
+    }
+
+    /// Returns an iterator over the entries of the map in ascending key order.
+    pub fn iter(&self) -> Iter<'_, V, K> {
+        let len = self.len();
+        Iter {
+            cursor: Cursor::new(&self.nodes[..], self.root, (K::MIN, K::MAX), (len, len)),
+        }
+    }
+
+    /// Returns an iterator over the entries of the map in ascending key order, with mutable
+    /// references to the values.
+    ///
+    /// The values are borrowed out of the node storage all at once, so unlike
+    /// [`iter`](Self::iter), this takes `O(n)` time and memory before the first entry.
+    pub fn iter_mut(&mut self) -> IterMut<'_, V, K> {
+        let (root, len) = (self.root, self.len());
+        IterMut {
+            cursor: Cursor::new(self.nodes_mut(), root, (K::MIN, K::MAX), (len, len)),
+        }
+    }
+
+    /// Returns an iterator over the entries with keys in `range`, in ascending key order.
+    pub fn range(&self, range: impl RangeBounds<K>) -> Range<'_, V, K> {
+        let range = inclusive_bounds(range);
+        Range {
+            cursor: Cursor::new(&self.nodes[..], self.root, range, (0, self.len())),
+        }
+    }
+
+    /// Returns an iterator over the entries with keys in `range`, in ascending key order, with
+    /// mutable references to the values. Like [`iter_mut`](Self::iter_mut), this first borrows
+    /// all values in `O(n)` time.
+    pub fn range_mut(&mut self, range: impl RangeBounds<K>) -> RangeMut<'_, V, K> {
+        let (range, root, len) = (inclusive_bounds(range), self.root, self.len());
+        RangeMut {
+            cursor: Cursor::new(self.nodes_mut(), root, range, (0, len)),
+        }
+    }
+
+    /// Returns the nodes with the values of the leaves borrowed mutably, for [`IterMut`] and
+    /// [`RangeMut`].
+    fn nodes_mut(&mut self) -> Vec<NodeMut<'_, V, K>> {
+        (self.nodes.iter_mut())
+            .map(|node| match node {
+                Node::Leaf(LeafNode { key, value }) => NodeMut::Leaf(*key, value),
+                Node::Internal(internal) => NodeMut::Internal(*internal),
+                Node::Vacant => NodeMut::Vacant,
+            })
+            .collect()
+    }
+
+    /// Returns an iterator over the keys of the map in ascending order.
+    pub fn keys(&self) -> Keys<'_, V, K> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Returns an iterator over the values of the map in ascending key order.
+    pub fn values(&self) -> Values<'_, V, K> {
+        Values { inner: self.iter() }
+    }
+
+    /// Returns an iterator over mutable references to the values of the map in ascending key
+    /// order.
+    pub fn values_mut(&mut self) -> ValuesMut<'_, V, K> {
+        ValuesMut {
+            inner: self.iter_mut(),
+        }
+    }
+
+    /// Consumes the map, returning its keys in ascending order.
+    pub fn into_keys(self) -> IntoKeys<V, K> {
+        IntoKeys {
+            inner: self.into_iter(),
+        }
+    }
+
+    /// Consumes the map, returning its values in ascending key order.
+    pub fn into_values(self) -> IntoValues<V, K> {
+        IntoValues {
+            inner: self.into_iter(),
+        }
+    }
+}
+
+/// A node as seen by [`IterMut`] and [`RangeMut`], which borrow the values of all leaves up front
+/// and hand them out as their entries come up.
+enum NodeMut<'a, V, K> {
+    Leaf(K, &'a mut V),
+    Internal(InternalNode<K>),
+    /// A vacant slot, or a node that has already been opened.
+    Vacant,
+}
+
+/// The nodes of a map as seen by a [`Cursor`], through their indices.
+impl<'a, V, K: PatriciaKey> Tree<K> for &'a [Node<V, K>] {
+    type Node = u32;
+    type Entry = (K, &'a V);
+
+    fn prefix(&self, &index: &u32) -> (K, u32) {
+        self[index as usize].prefix()
+    }
+
+    fn open(&mut self, index: u32) -> Result<Self::Entry, [u32; 2]> {
+        let nodes: &'a [Node<V, K>] = self;
+        match &nodes[index as usize] {
+            Node::Leaf(LeafNode { key, value }) => Ok((*key, value)),
+            Node::Internal(InternalNode { left, right, .. }) => Err([*left, *right]),
+            Node::Vacant => unreachable!(),
+        }
+    }
+}
+
+impl<'a, V, K: PatriciaKey> Tree<K> for Vec<NodeMut<'a, V, K>> {
+    type Node = u32;
+    type Entry = (K, &'a mut V);
+
+    fn prefix(&self, &index: &u32) -> (K, u32) {
+        match &self[index as usize] {
+            NodeMut::Leaf(key, _) => (*key, K::BITS),
+            NodeMut::Internal(InternalNode {
+                key_prefix,
+                branch_bit,
+                ..
+            }) => (*key_prefix, *branch_bit as u32),
+            NodeMut::Vacant => unreachable!(),
+        }
+    }
+
+    fn open(&mut self, index: u32) -> Result<Self::Entry, [u32; 2]> {
+        match mem::replace(&mut self[index as usize], NodeMut::Vacant) {
+            NodeMut::Leaf(key, value) => Ok((key, value)),
+            NodeMut::Internal(InternalNode { left, right, .. }) => Err([left, right]),
+            NodeMut::Vacant => unreachable!(),
+        }
+    }
+}
+
+/// The nodes of a map consumed by [`IntoIter`], which takes each node out of its slot as it is
+/// opened.
+impl<V, K: PatriciaKey> Tree<K> for Vec<Node<V, K>> {
+    type Node = u32;
+    type Entry = (K, V);
+
+    fn prefix(&self, &index: &u32) -> (K, u32) {
+        self[index as usize].prefix()
+    }
+
+    fn open(&mut self, index: u32) -> Result<Self::Entry, [u32; 2]> {
+        match mem::replace(&mut self[index as usize], Node::Vacant) {
+            Node::Leaf(LeafNode { key, value }) => Ok((key, value)),
+            Node::Internal(InternalNode { left, right, .. }) => Err([left, right]),
+            Node::Vacant => unreachable!(),
+        }
+    }
+}
+
+impl<V, K: PatriciaKey> Default for PatriciaArenaMap<V, K> {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            root: None,
+            nodes: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+}
+
+impl<V, K: PatriciaKey> FromIterator<(K, V)> for PatriciaArenaMap<V, K> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::default();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<V, K: PatriciaKey> Extend<(K, V)> for PatriciaArenaMap<V, K> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+duplicate! {
+  [
+    name        nodes                     item;
+    [Iter]      [&'a [Node<V, K>]]        [&'a V];
+    [IterMut]   [Vec<NodeMut<'a, V, K>>]  [&'a mut V];
+    [Range]     [&'a [Node<V, K>]]        [&'a V];
+    [RangeMut]  [Vec<NodeMut<'a, V, K>>]  [&'a mut V];
+  ]
+    pub struct name<'a, V, K = u64> {
+        cursor: Cursor<nodes, u32, K>,
+    }
+
+    impl<'a, V, K: PatriciaKey> Iterator for name<'a, V, K> {
+        type Item = (K, item);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.cursor.next()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.cursor.size_hint()
+        }
+    }
+
+    impl<'a, V, K: PatriciaKey> DoubleEndedIterator for name<'a, V, K> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.cursor.next_back()
+        }
+    }
+
+    impl<'a, V, K: PatriciaKey> FusedIterator for name<'a, V, K> {}
+}
+
+/// Unlike the ranges, these visit the whole map, so they know how many entries are left.
+#[duplicate_item(name; [Iter]; [IterMut];)]
+impl<'a, V, K: PatriciaKey> ExactSizeIterator for name<'a, V, K> {}
+
+duplicate! {
+  [
+    name        inner_iter  item         project;
+    [Keys]      [Iter]      [K]          [|(key, _)| key];
+    [Values]    [Iter]      [&'a V]      [|(_, value)| value];
+    [ValuesMut] [IterMut]   [&'a mut V]  [|(_, value)| value];
+  ]
+    pub struct name<'a, V, K = u64> {
+        inner: inner_iter<'a, V, K>,
+    }
+
+    impl<'a, V, K: PatriciaKey> Iterator for name<'a, V, K> {
+        type Item = item;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(project)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<'a, V, K: PatriciaKey> DoubleEndedIterator for name<'a, V, K> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back().map(project)
+        }
+    }
+
+    impl<'a, V, K: PatriciaKey> ExactSizeIterator for name<'a, V, K> {}
+
+    impl<'a, V, K: PatriciaKey> FusedIterator for name<'a, V, K> {}
+}
+
+pub struct IntoIter<V, K = u64> {
+    cursor: Cursor<Vec<Node<V, K>>, u32, K>,
+}
+
+impl<V, K: PatriciaKey> Iterator for IntoIter<V, K> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.cursor.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.cursor.size_hint()
+    }
+}
+
+impl<V, K: PatriciaKey> DoubleEndedIterator for IntoIter<V, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.cursor.next_back()
+    }
+}
+
+impl<V, K: PatriciaKey> ExactSizeIterator for IntoIter<V, K> {}
+
+impl<V, K: PatriciaKey> FusedIterator for IntoIter<V, K> {}
+
+duplicate! {
+  [
+    name         item  project;
+    [IntoKeys]   [K]   [|(key, _)| key];
+    [IntoValues] [V]   [|(_, value)| value];
+  ]
+    pub struct name<V, K = u64> {
+        inner: IntoIter<V, K>,
+    }
+
+    impl<V, K: PatriciaKey> Iterator for name<V, K> {
+        type Item = item;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(project)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<V, K: PatriciaKey> DoubleEndedIterator for name<V, K> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back().map(project)
+        }
+    }
+
+    impl<V, K: PatriciaKey> ExactSizeIterator for name<V, K> {}
+
+    impl<V, K: PatriciaKey> FusedIterator for name<V, K> {}
+}
+
+impl<'a, V, K: PatriciaKey> IntoIterator for &'a PatriciaArenaMap<V, K> {
+    type Item = (K, &'a V);
+    type IntoIter = Iter<'a, V, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, V, K: PatriciaKey> IntoIterator for &'a mut PatriciaArenaMap<V, K> {
+    type Item = (K, &'a mut V);
+    type IntoIter = IterMut<'a, V, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<V, K: PatriciaKey> IntoIterator for PatriciaArenaMap<V, K> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<V, K>;
+
+    /// Consumes the map, returning its entries in ascending key order.
+    fn into_iter(self) -> Self::IntoIter {
+        let len = self.len();
+        IntoIter {
+            cursor: Cursor::new(self.nodes, self.root, (K::MIN, K::MAX), (len, len)),
+        }
+    }
+}
+
+/// Formats the map like a map literal, with its entries in ascending key order.
+impl<V: Debug, K: PatriciaKey> Debug for PatriciaArenaMap<V, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<V: PartialEq, K: PatriciaKey> PartialEq for PatriciaArenaMap<V, K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<V: Eq, K: PatriciaKey> Eq for PatriciaArenaMap<V, K> {}
+
+impl<V: PartialOrd, K: PatriciaKey> PartialOrd for PatriciaArenaMap<V, K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+/// Maps are compared lexicographically as sequences of `(key, value)` pairs in ascending key
+/// order.
+impl<V: Ord, K: PatriciaKey> Ord for PatriciaArenaMap<V, K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+impl<V: Hash, K: PatriciaKey> Hash for PatriciaArenaMap<V, K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        for entry in self.iter() {
+            entry.hash(state);
+        }
+    }
+}
+
+impl<V, K: PatriciaKey> Index<K> for PatriciaArenaMap<V, K> {
+    type Output = V;
+
+    /// Returns a reference to the value of `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is not in the map.
+    fn index(&self, key: K) -> &V {
+        match self.get(key) {
+            Some(value) => value,
+            None => panic!("key {:?} not found in PatriciaArenaMap", key),
+        }
+    }
+}
+
+/// Builds a map with `u64` keys from `(key, value)` pairs, where later values replace earlier
+/// ones with the same key. Maps with other key types are built with [`FromIterator`].
+impl<V, const N: usize> From<[(u64, V); N]> for PatriciaArenaMap<V> {
+    fn from(entries: [(u64, V); N]) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+impl<V, K: PatriciaKey> From<BTreeMap<K, V>> for PatriciaArenaMap<V, K> {
+    fn from(map: BTreeMap<K, V>) -> Self {
+        let mut arena = Self::with_capacity(map.len());
+        arena.extend(map);
+        arena
+    }
+}
+
+impl<V, K: PatriciaKey> From<PatriciaArenaMap<V, K>> for BTreeMap<K, V> {
+    fn from(map: PatriciaArenaMap<V, K>) -> Self {
+        map.into_iter().collect()
+    }
+}
+
+pub enum Entry<'a, V, K = u64> {
+    Occupied(OccupiedEntry<'a, V, K>),
+    Vacant(VacantEntry<'a, V, K>),
+}
+
+pub struct OccupiedEntry<'a, V, K = u64> {
+    map: &'a mut PatriciaArenaMap<V, K>,
+    /// The index of the leaf of the entry.
+    index: u32,
+}
+
+pub struct VacantEntry<'a, V, K = u64> {
+    map: &'a mut PatriciaArenaMap<V, K>,
+    key: K,
+}
+
+impl<'a, V, K: PatriciaKey> Entry<'a, V, K> {
+    pub fn key(&self) -> K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        self.or_insert_with_key(|_| default())
+    }
+
+    pub fn or_insert_with_key<F: FnOnce(K) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let value = default(entry.key());
+                entry.insert(value)
+            }
+        }
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Entry::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, V: Default, K: PatriciaKey> Entry<'a, V, K> {
+    pub fn or_default(self) -> &'a mut V {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<'a, V, K: PatriciaKey> OccupiedEntry<'a, V, K> {
+    pub fn key(&self) -> K {
+        self.map.leaf(self.index).key
+    }
+
+    pub fn get(&self) -> &V {
+        &self.map.leaf(self.index).value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.map.leaf_mut(self.index).value
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.map.leaf_mut(self.index).value
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        let key = self.key();
+        self.map.remove(key).unwrap()
+    }
+}
+
+impl<'a, V, K: PatriciaKey> VacantEntry<'a, V, K> {
+    pub fn key(&self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        let (index, _) = self.map.insert_leaf(self.key, value);
+        &mut self.map.leaf_mut(index).value
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::{Entry, PatriciaArenaMap};
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use std::collections::{BTreeMap, HashMap};
+    use std::format;
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    use std::vec::Vec;
+
+    #[test]
+    fn test_insert_remove() {
+        let mut map = PatriciaArenaMap::new();
+        assert_eq!(map.insert(3, "a"), None);
+        assert_eq!(map.insert(1, "b"), None);
+        assert_eq!(map.insert(3, "c"), Some("a"));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(3), Some(&"c"));
+        assert_eq!(map.remove(3), Some("c"));
+        assert_eq!(map.remove(3), None);
+        assert_eq!(map.get(1), Some(&"b"));
+        assert_eq!(map.remove(1), Some("b"));
+        assert!(map.is_empty());
+        assert_eq!(map.iter().next(), None);
+    }
+
+    #[test]
+    fn test_entry() {
+        let keys = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5];
+        let mut map = PatriciaArenaMap::<usize>::new();
+        for &key in keys.iter() {
+            map.entry(key).and_modify(|count| *count += 1).or_insert(1);
+        }
+        assert_eq!(map.len(), 7);
+        assert_eq!(map[5], 3);
+        assert_eq!(*map.entry(7).or_insert_with_key(|key| key as usize * 2), 14);
+        assert_eq!(*map.entry(7).or_default(), 14);
+        match map.entry(5) {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.insert(10), 3);
+                assert_eq!(entry.remove(), 10);
+            }
+            Entry::Vacant(_) => unreachable!(),
+        }
+        assert_eq!(map.get(5), None);
+
+        assert_eq!(map.first_entry().map(|entry| entry.remove()), Some(2));
+        *map.last_entry().unwrap().get_mut() += 1;
+        assert_eq!(map.pop_last(), Some((9, 2)));
+        assert_eq!(map.pop_first(), Some((2, 1)));
+        assert_eq!(
+            map.into_iter().collect::<Vec<_>>(),
+            [(3, 2), (4, 1), (6, 1), (7, 14)]
+        );
+    }
+
+    #[test]
+    fn test_traits() {
+        fn hash_of(map: &PatriciaArenaMap<u64>) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            map.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let a: PatriciaArenaMap<u64> = (0..10).map(|key| (key << 20, key)).collect();
+        let mut b: PatriciaArenaMap<u64> = (0..10).rev().map(|key| (key << 20, key)).collect();
+        b.insert(1 << 40, 0);
+        b.remove(1 << 40);
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+        b.insert(1 << 40, 0);
+        assert!(a < b);
+        assert_eq!(a[3 << 20], 3);
+        assert_eq!(
+            BTreeMap::from(a.clone()),
+            BTreeMap::from_iter(a.iter().map(|(k, &v)| (k, v)))
+        );
+
+        let map = PatriciaArenaMap::from([(2, "b"), (1, "a"), (1 << 40, "c")]);
+        assert_eq!(
+            format!("{:?}", map),
+            r#"{1: "a", 2: "b", 1099511627776: "c"}"#
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "key 2 not found")]
+    fn test_index_missing() {
+        let map = PatriciaArenaMap::from([(1, "a")]);
+        let _ = map[2];
+    }
+
+    #[test]
+    fn test_reuses_slots() {
+        let mut map = PatriciaArenaMap::new();
+        for key in 0..100 {
+            map.insert(key, ());
+        }
+        let allocated = map.nodes.len();
+        for _ in 0..10 {
+            for key in 0..50 {
+                map.remove(key);
+            }
+            for key in 0..50 {
+                map.insert(key, ());
+            }
+        }
+        assert_eq!(map.nodes.len(), allocated);
+        assert_eq!(map.len(), 100);
+    }
+
+    #[test]
+    fn test_allocated_nodes() {
+        let mut map = PatriciaArenaMap::new();
+        assert_eq!(map.allocated_nodes(), 0);
+        for key in 0..100 {
+            map.insert(key * 37, ());
+            assert_eq!(map.allocated_nodes(), 2 * map.len() - 1);
+        }
+        for key in 0..60 {
+            map.remove(key * 37);
+            assert_eq!(map.allocated_nodes(), 2 * map.len() - 1);
+        }
+        assert!(map.nodes.len() > map.allocated_nodes());
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut map = PatriciaArenaMap::<u64>::with_capacity(1000);
+        let capacity = map.nodes.capacity();
+        assert!(capacity >= 1999);
+        for key in 0..1000 {
+            assert_eq!(map.insert(key * 7919, key), None);
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.nodes.capacity(), capacity);
+        assert!(map
+            .iter()
+            .map(|(k, v)| (k, *v))
+            .eq((0..1000).map(|key| (key * 7919, key))));
+
+        let map = PatriciaArenaMap::<(), u32>::with_capacity(0);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_shrink_to_fit() {
+        let mut map = PatriciaArenaMap::new();
+        for key in 0..1000 {
+            map.insert(key * 37, key);
+        }
+        for key in 0..990 {
+            map.remove(key * 37);
+        }
+        let usage = map.memory_usage();
+        map.shrink_to_fit();
+        assert!(map.memory_usage() < usage / 10);
+        assert_eq!(map.nodes.len(), 19);
+        assert!(map.free.is_empty());
+        assert!(map
+            .iter()
+            .map(|(k, v)| (k, *v))
+            .eq((990..1000).map(|key| (key * 37, key))));
+
+        // The map keeps working after being compacted.
+        assert_eq!(map.insert(5, 5), None);
+        assert_eq!(map.remove(990 * 37), Some(990));
+        assert_eq!(map.get(5), Some(&5));
+        assert_eq!(map.len(), 10);
+
+        map.clear();
+        map.shrink_to_fit();
+        assert_eq!(map.memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_million_keys() {
+        let mut map = PatriciaArenaMap::new();
+        let mut reference = HashMap::new();
+        let mut key = 1u64;
+        for i in 0..1_000_000u64 {
+            // A xorshift sequence, so the keys are spread over the whole key space.
+            key ^= key << 13;
+            key ^= key >> 7;
+            key ^= key << 17;
+            let key = if i % 4 == 0 { i } else { key };
+            assert_eq!(map.insert(key, i), reference.insert(key, i));
+        }
+        assert_eq!(map.len(), reference.len());
+        for (&key, value) in reference.iter() {
+            assert_eq!(map.get(key), Some(value));
+        }
+        for key in reference.keys().step_by(3) {
+            assert!(map.remove(*key).is_some());
+        }
+        for (i, (&key, value)) in reference.iter().enumerate() {
+            let expected = if i % 3 == 0 { None } else { Some(value) };
+            assert_eq!(map.get(key), expected);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_against_btree_map(
+            operations in vec((any::<bool>(), 0u64..64, any::<u32>()), 0..200),
+        ) {
+            let mut map = PatriciaArenaMap::new();
+            let mut reference = BTreeMap::new();
+            for (insert, key, value) in operations {
+                if insert {
+                    assert_eq!(map.insert(key, value), reference.insert(key, value));
+                } else {
+                    assert_eq!(map.remove(key), reference.remove(&key));
+                }
+                assert_eq!(map.len(), reference.len());
+            }
+            assert!(map.iter().eq(reference.iter().map(|(&k, v)| (k, v))));
+            assert_eq!(map.nodes.len() - map.free.len(), (2 * map.len()).saturating_sub(1));
+
+            map.shrink_to_fit();
+            assert!(map.iter().eq(reference.iter().map(|(&k, v)| (k, v))));
+            assert_eq!(map.nodes.len(), (2 * map.len()).saturating_sub(1));
+        }
+
+        #[test]
+        fn test_ordered_against_btree_map(
+            keys in vec(any::<u64>().prop_map(|key| key >> (key % 64)), 0..200),
+            a in any::<u64>(),
+            b in any::<u64>(),
+        ) {
+            let mut map: PatriciaArenaMap<u64> = keys.iter().map(|&key| (key, key)).collect();
+            let mut reference: BTreeMap<u64, u64> = keys.iter().map(|&key| (key, key)).collect();
+
+            assert_eq!(map.first_key_value(), reference.first_key_value().map(|(&k, v)| (k, v)));
+            assert_eq!(map.last_key_value(), reference.last_key_value().map(|(&k, v)| (k, v)));
+            assert_eq!(map.ceiling(a), reference.range(a..).next().map(|(&k, v)| (k, v)));
+            assert_eq!(map.floor(a), reference.range(..=a).next_back().map(|(&k, v)| (k, v)));
+            let (a, b) = (a.min(b), a.max(b));
+            assert!(map.range(a..=b).eq(reference.range(a..=b).map(|(&k, v)| (k, v))));
+            assert!(map.range(a..).rev().eq(reference.range(a..).rev().map(|(&k, v)| (k, v))));
+            assert!(map.keys().eq(reference.keys().copied()));
+
+            for (key, value) in map.range_mut(..a) {
+                *value = key.wrapping_mul(3);
+            }
+            for (key, value) in reference.range_mut(..a) {
+                *value = key.wrapping_mul(3);
+            }
+            for value in map.values_mut().rev().step_by(2) {
+                *value = value.wrapping_add(1);
+            }
+            for value in reference.values_mut().rev().step_by(2) {
+                *value = value.wrapping_add(1);
+            }
+            assert!(map.iter().eq(reference.iter().map(|(&k, v)| (k, v))));
+
+            map.retain(|key, value| (key ^ *value) % 3 != 0);
+            reference.retain(|key, value| (key ^ *value) % 3 != 0);
+            assert_eq!(map.len(), reference.len());
+            assert!(map.values().eq(reference.values()));
+            assert!(map.into_iter().rev().eq(reference.into_iter().rev()));
+        }
+    }
+}
@@ -0,0 +1,632 @@
+use crate::replace_with_or_abort;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::{self, Debug};
+use core::iter::FusedIterator;
+use core::mem;
+use duplicate::{duplicate, duplicate_item};
+
+/// Bits used to encode one byte of a key: a bit marking that the byte is present, followed by
+/// the bits of the byte itself, most significant first. A key that is a proper prefix of another
+/// key first differs from it in the presence bit right after its end, so every key can be stored
+/// in a leaf, and traversing the tree left to right gives the keys in lexicographic order.
+const BITS_PER_BYTE: usize = 9;
+
+#[derive(Clone)]
+struct LeafNode<V> {
+    key: Vec<u8>,
+    value: V,
+}
+
+struct InternalNode<V> {
+    /// All keys below this node agree on the bits before `bit`, and differ in `bit`.
+    bit: usize,
+    left: Box<Node<V>>,
+    right: Box<Node<V>>,
+}
+
+enum Node<V> {
+    Leaf(LeafNode<V>),
+    Internal(InternalNode<V>),
+}
+
+/// A map from byte strings of arbitrary length to values of type `V`, stored in a Patricia tree
+/// that branches on the bits of the keys from the first byte onwards.
+pub struct PatriciaByteMap<V> {
+    size: usize,
+    root: Option<Box<Node<V>>>,
+}
+
+/// Returns bit `bit` of the encoded `key`. Bits past the end of the key are unset.
+fn get_bit(key: &[u8], bit: usize) -> bool {
+    let (index, offset) = (bit / BITS_PER_BYTE, bit % BITS_PER_BYTE);
+    match key.get(index) {
+        None => false,
+        Some(_) if offset == 0 => true,
+        Some(byte) => byte >> (BITS_PER_BYTE - 1 - offset) & 1 == 1,
+    }
+}
+
+/// Returns the first bit in which the encodings of the distinct keys `a` and `b` differ.
+fn branch_bit(a: &[u8], b: &[u8]) -> usize {
+    let index = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    match (a.get(index), b.get(index)) {
+        (Some(x), Some(y)) => index * BITS_PER_BYTE + 1 + (x ^ y).leading_zeros() as usize,
+        _ => index * BITS_PER_BYTE,
+    }
+}
+
+impl<V> PatriciaByteMap<V> {
+    pub fn new() -> Self {
+        Self {
+            size: 0,
+            root: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.size = 0;
+    }
+
+    /// Returns the leaf reached by following the bits of `key`. This is the only leaf that can
+    /// hold `key`, but it may hold a different key.
+    #[duplicate_item(
+      method           reference(type) as_deref(v);
+      [find_leaf]      [& type]        [v.as_deref()];
+      [find_leaf_mut]  [&mut type]     [v.as_deref_mut()];
+    )]
+    #[allow(clippy::needless_arbitrary_self_type)]
+    fn method(self: reference([Self]), key: &[u8]) -> Option<reference([LeafNode<V>])> {
+        let mut node = as_deref([self.root])?;
+        loop {
+            match node {
+                Node::Leaf(leaf) => return Some(leaf),
+                Node::Internal(InternalNode { bit, left, right }) => {
+                    node = if get_bit(key, *bit) { right } else { left };
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&V> {
+        let key = key.as_ref();
+        match self.find_leaf(key) {
+            Some(LeafNode { key: k, value }) if k == key => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: impl AsRef<[u8]>) -> Option<&mut V> {
+        let key = key.as_ref();
+        match self.find_leaf_mut(key) {
+            Some(LeafNode { key: k, value }) if k == key => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns whether `key` is in the map.
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns the stored entry whose key is the longest prefix of `key`, including `key`
+    /// itself.
+    pub fn longest_prefix_match(&self, key: impl AsRef<[u8]>) -> Option<(&[u8], &V)> {
+        let key = key.as_ref();
+        let mut res = None;
+        let mut node = self.root.as_deref();
+        while let Some(n) = node {
+            match n {
+                Node::Leaf(LeafNode { key: k, value }) => {
+                    if key.starts_with(k) {
+                        res = Some((k.as_slice(), value));
+                    }
+                    break;
+                }
+                Node::Internal(InternalNode { bit, left, right }) => {
+                    // A stored prefix of `key` that is shorter than `key` ends where `key` has
+                    // its next byte, so it is the only key on the left of a presence bit.
+                    if bit % BITS_PER_BYTE == 0 {
+                        if let Node::Leaf(LeafNode { key: k, value }) = left.as_ref() {
+                            if key.starts_with(k) {
+                                res = Some((k.as_slice(), value));
+                            }
+                        }
+                    }
+                    node = Some(if get_bit(key, *bit) { right } else { left });
+                }
+            }
+        }
+        res
+    }
+
+    pub fn insert(&mut self, key: impl AsRef<[u8]>, value: V) -> Option<V> {
+        let key = key.as_ref();
+        let bit = match self.find_leaf_mut(key) {
+            None => {
+                self.root = Some(Box::new(Node::Leaf(LeafNode {
+                    key: key.to_vec(),
+                    value,
+                })));
+                self.size += 1;
+                return None;
+            }
+            Some(LeafNode { key: k, value: v }) if k == key => return Some(mem::replace(v, value)),
+            Some(LeafNode { key: k, .. }) => branch_bit(k, key),
+        };
+
+        // The new leaf goes above the first node that branches on a later bit than `bit`.
+        let mut node = self.root.as_deref_mut().unwrap();
+        while let Node::Internal(InternalNode { bit: b, .. }) = *node {
+            if b > bit {
+                break;
+            }
+            node = match node {
+                Node::Internal(InternalNode { right, .. }) if get_bit(key, b) => right,
+                Node::Internal(InternalNode { left, .. }) => left,
+                Node::Leaf { .. } => unreachable!(),
+            };
+        }
+
+        let leaf = Node::Leaf(LeafNode {
+            key: key.to_vec(),
+            value,
+        });
+        let is_left = !get_bit(key, bit);
+        replace_with_or_abort(node, |old_node| {
+            let (left, right) = if is_left {
+                (leaf, old_node)
+            } else {
+                (old_node, leaf)
+            };
+            Node::Internal(InternalNode {
+                bit,
+                left: Box::new(left),
+                right: Box::new(right),
+            })
+        });
+        self.size += 1;
+        None
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Option<V> {
+        fn aux<V>(mut node: &mut Node<V>, key: &[u8]) -> Option<V> {
+            // Descend to the parent of the leaf that can hold `key`.
+            let is_left = loop {
+                let Node::Internal(InternalNode { bit, left, right }) = &*node else {
+                    return None;
+                };
+                let is_left = !get_bit(key, *bit);
+                match if is_left { left } else { right }.as_ref() {
+                    Node::Leaf(LeafNode { key: k, .. }) if k == key => break is_left,
+                    Node::Leaf { .. } => return None,
+                    Node::Internal { .. } => {}
+                }
+                node = match node {
+                    Node::Internal(InternalNode { left, .. }) if is_left => left,
+                    Node::Internal(InternalNode { right, .. }) => right,
+                    Node::Leaf { .. } => unreachable!(),
+                };
+            };
+
+            let mut res = None;
+            replace_with_or_abort(node, |old_node| match old_node {
+                Node::Internal(InternalNode { left, right, .. }) => {
+                    let (leaf, sibling) = if is_left {
+                        (left, right)
+                    } else {
+                        (right, left)
+                    };
+                    if let Node::Leaf(LeafNode { value, .. }) = *leaf {
+                        res = Some(value);
+                    }
+                    *sibling
+                }
+                Node::Leaf { .. } => unreachable!(),
+            });
+            res
+        }
+
+        let key = key.as_ref();
+        let res = match self.root.as_deref_mut() {
+            None => None,
+            Some(Node::Leaf(LeafNode { key: k, .. })) if k == key => {
+                match self.root.take().map(|root| *root) {
+                    Some(Node::Leaf(LeafNode { value, .. })) => Some(value),
+                    _ => unreachable!(),
+                }
+            }
+            Some(node) => aux(node, key),
+        };
+        self.size -= res.is_some() as usize;
+        res
+    }
+
+    /// Returns an iterator over the entries of the map in lexicographic key order.
+    pub fn iter(&self) -> Iter<'_, V> {
+        Iter {
+            walk: Walk::new(self.root.as_deref()),
+            len: self.len(),
+        }
+    }
+
+    /// Returns an iterator over the entries whose keys start with `prefix`, in lexicographic key
+    /// order.
+    pub fn prefix_iter(&self, prefix: impl AsRef<[u8]>) -> PrefixIter<'_, V> {
+        let prefix = prefix.as_ref();
+        // Keys starting with `prefix` agree on its first bits, so they form the subtree below the
+        // first node that branches on a later bit.
+        let prefix_bits = prefix.len() * BITS_PER_BYTE;
+        let mut node = self.root.as_deref();
+        while let Some(Node::Internal(InternalNode { bit, left, right })) = node {
+            if *bit >= prefix_bits {
+                break;
+            }
+            node = Some(if get_bit(prefix, *bit) { right } else { left });
+        }
+
+        let mut leaf = node;
+        while let Some(Node::Internal(InternalNode { left, .. })) = leaf {
+            leaf = Some(left);
+        }
+        let root = match leaf {
+            Some(Node::Leaf(LeafNode { key, .. })) if key.starts_with(prefix) => node,
+            _ => None,
+        };
+        PrefixIter {
+            walk: Walk::new(root),
+            len: self.len(),
+        }
+    }
+}
+
+/// Formats the map like a map literal, with its entries in lexicographic key order. This walks
+/// the tree with the iterator rather than recursing into it, since deep trees are possible.
+impl<V: Debug> Debug for PatriciaByteMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<V> Default for PatriciaByteMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Copies the tree bottom-up with explicit stacks, for the same reason as [`Drop`] below.
+impl<V: Clone> Clone for PatriciaByteMap<V> {
+    fn clone(&self) -> Self {
+        // Each internal node is visited twice: first to queue its children, then, once they are
+        // cloned, to join them.
+        let mut stack: Vec<(&Node<V>, bool)> = self.root.iter().map(|n| (&**n, false)).collect();
+        let mut cloned: Vec<Box<Node<V>>> = Vec::new();
+        while let Some((node, children_cloned)) = stack.pop() {
+            match node {
+                Node::Leaf(leaf) => cloned.push(Box::new(Node::Leaf(leaf.clone()))),
+                Node::Internal(InternalNode { bit, .. }) if children_cloned => {
+                    let right = cloned.pop().unwrap();
+                    let left = cloned.pop().unwrap();
+                    cloned.push(Box::new(Node::Internal(InternalNode {
+                        bit: *bit,
+                        left,
+                        right,
+                    })));
+                }
+                Node::Internal(InternalNode { left, right, .. }) => {
+                    stack.push((node, true));
+                    stack.push((right, false));
+                    stack.push((left, false));
+                }
+            }
+        }
+
+        Self {
+            size: self.size,
+            root: cloned.pop(),
+        }
+    }
+}
+
+/// Tears the tree down with an explicit stack. A key that is a prefix of another adds a level to
+/// the tree, so the recursive drops of the nested boxes could overflow the stack.
+impl<V> Drop for PatriciaByteMap<V> {
+    fn drop(&mut self) {
+        let mut stack: Vec<Box<Node<V>>> = self.root.take().into_iter().collect();
+        while let Some(node) = stack.pop() {
+            if let Node::Internal(InternalNode { left, right, .. }) = *node {
+                stack.push(right);
+                stack.push(left);
+            }
+        }
+    }
+}
+
+impl<K: AsRef<[u8]>, V> FromIterator<(K, V)> for PatriciaByteMap<V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+/// Walks a subtree from both ends at once. Traversing the tree left to right gives the keys in
+/// lexicographic order, so each end keeps the subtrees it has yet to visit on a stack, nearest on
+/// top, and only descends as far as its next entry.
+struct Walk<'a, V> {
+    front: Vec<&'a Node<V>>,
+    back: Vec<&'a Node<V>>,
+    /// The keys last yielded from each end, which the other end must not reach.
+    front_key: Option<&'a [u8]>,
+    back_key: Option<&'a [u8]>,
+}
+
+impl<'a, V> Walk<'a, V> {
+    fn new(root: Option<&'a Node<V>>) -> Self {
+        Self {
+            front: root.into_iter().collect(),
+            back: root.into_iter().collect(),
+            front_key: None,
+            back_key: None,
+        }
+    }
+
+    #[duplicate_item(
+      method       this     this_key     other_key    first    second   before(a, b);
+      [next]       [front]  [front_key]  [back_key]   [left]   [right]  [a < b];
+      [next_back]  [back]   [back_key]   [front_key]  [right]  [left]   [a > b];
+    )]
+    fn method(&mut self) -> Option<(&'a [u8], &'a V)> {
+        while let Some(node) = self.this.pop() {
+            match node {
+                Node::Leaf(LeafNode { key, value }) => {
+                    if self
+                        .other_key
+                        .is_some_and(|other| !(before([key.as_slice()], [other])))
+                    {
+                        break;
+                    }
+                    self.this_key = Some(key);
+                    return Some((key, value));
+                }
+                Node::Internal(InternalNode { left, right, .. }) => {
+                    self.this.push(second);
+                    self.this.push(first);
+                }
+            }
+        }
+        // The ends have met.
+        self.front.clear();
+        self.back.clear();
+        None
+    }
+}
+
+duplicate! {
+  [
+    name          lower(len);
+    [Iter]        [len];
+    [PrefixIter]  [0];
+  ]
+    pub struct name<'a, V> {
+        walk: Walk<'a, V>,
+        /// The number of entries left, or an upper bound on it for a [`PrefixIter`].
+        len: usize,
+    }
+
+    impl<'a, V> Iterator for name<'a, V> {
+        type Item = (&'a [u8], &'a V);
+
+        fn next(&mut self) -> Option<Self::Item> {
+            let entry = self.walk.next()?;
+            self.len -= 1;
+            Some(entry)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (lower([self.len]), Some(self.len))
+        }
+    }
+
+    impl<'a, V> DoubleEndedIterator for name<'a, V> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            let entry = self.walk.next_back()?;
+            self.len -= 1;
+            Some(entry)
+        }
+    }
+
+    impl<'a, V> FusedIterator for name<'a, V> {}
+}
+
+impl<'a, V> ExactSizeIterator for Iter<'a, V> {}
+
+impl<'a, V> IntoIterator for &'a PatriciaByteMap<V> {
+    type Item = (&'a [u8], &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::PatriciaByteMap;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_exact_match() {
+        let map: PatriciaByteMap<u32> = [("cat", 1), ("car", 2), ("dog", 3)].into_iter().collect();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get("cat"), Some(&1));
+        assert_eq!(map.get("car"), Some(&2));
+        assert_eq!(map.get("dog"), Some(&3));
+        assert_eq!(map.get("ca"), None);
+        assert_eq!(map.get("cats"), None);
+        assert_eq!(map.get(""), None);
+        assert!(map.contains_key("car"));
+        assert!(!map.contains_key("ca"));
+        assert_eq!(
+            format!("{:?}", map),
+            "{[99, 97, 114]: 2, [99, 97, 116]: 1, [100, 111, 103]: 3}"
+        );
+    }
+
+    #[test]
+    fn test_prefix_keys() {
+        let mut map = PatriciaByteMap::new();
+        assert_eq!(map.insert("", 0), None);
+        assert_eq!(map.insert("ca", 1), None);
+        assert_eq!(map.insert("cat", 2), None);
+        assert_eq!(map.insert("cats", 3), None);
+        assert_eq!(map.insert("cat", 4), Some(2));
+        assert_eq!(map.len(), 4);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            vec![
+                (&b""[..], &0),
+                (&b"ca"[..], &1),
+                (&b"cat"[..], &4),
+                (&b"cats"[..], &3)
+            ]
+        );
+        assert_eq!(map.remove("cat"), Some(4));
+        assert_eq!(map.remove("cat"), None);
+        assert_eq!(map.get("cats"), Some(&3));
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let map: PatriciaByteMap<u32> = [("cat", 1), ("car", 2), ("dog", 3), ("c", 4)]
+            .into_iter()
+            .collect();
+        assert_eq!(map.longest_prefix_match("cat"), Some((&b"cat"[..], &1)));
+        assert_eq!(map.longest_prefix_match("catalog"), Some((&b"cat"[..], &1)));
+        assert_eq!(map.longest_prefix_match("cargo"), Some((&b"car"[..], &2)));
+        assert_eq!(map.longest_prefix_match("cow"), Some((&b"c"[..], &4)));
+        assert_eq!(
+            map.longest_prefix_match("doghouse"),
+            Some((&b"dog"[..], &3))
+        );
+        assert_eq!(map.longest_prefix_match("do"), None);
+        assert_eq!(map.longest_prefix_match("bird"), None);
+    }
+
+    #[test]
+    fn test_prefix_iter() {
+        let map: PatriciaByteMap<u32> = [("cat", 1), ("car", 2), ("dog", 3), ("cart", 4)]
+            .into_iter()
+            .collect();
+        let keys = |prefix: &str| map.prefix_iter(prefix).map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys("ca"), vec![&b"car"[..], b"cart", b"cat"]);
+        assert_eq!(keys("car"), vec![&b"car"[..], b"cart"]);
+        assert_eq!(keys("d"), vec![&b"dog"[..]]);
+        assert_eq!(keys(""), vec![&b"car"[..], b"cart", b"cat", b"dog"]);
+        assert!(keys("cow").is_empty());
+        assert!(keys("dogs").is_empty());
+    }
+
+    #[test]
+    fn test_deep_tree() {
+        // Every key is a prefix of the next one, so the tree is a chain with one level per key.
+        let map: PatriciaByteMap<usize> = (0..5000).map(|len| (vec![0; len], len)).collect();
+        assert_eq!(map.len(), 5000);
+        std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(move || {
+                let mut clone = map.clone();
+                assert!(clone.iter().eq(map.iter()));
+                assert_eq!(clone.insert(vec![0; 5000], 5000), None);
+                assert_eq!(clone.remove(vec![0; 4999]), Some(4999));
+                assert_eq!(clone.get(vec![0; 5000]), Some(&5000));
+                assert_eq!(clone.len(), 5000);
+                assert_eq!(map.get(vec![0; 4999]), Some(&4999));
+                assert!(format!("{:?}", clone).ends_with("]: 5000}"));
+                drop(map);
+                drop(clone);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    fn longest_prefix_match_reference(
+        reference: &BTreeMap<Vec<u8>, u32>,
+        key: &[u8],
+    ) -> Option<Vec<u8>> {
+        reference
+            .keys()
+            .filter(|k| key.starts_with(k))
+            .max_by_key(|k| k.len())
+            .cloned()
+    }
+
+    proptest! {
+        #[test]
+        fn test_against_btree_map(
+            keys in vec(vec(0u8..4, 0..5), 0..50),
+            removed in vec(vec(0u8..4, 0..5), 0..20),
+            queries in vec(vec(0u8..4, 0..7), 0..20),
+        ) {
+            let mut map = PatriciaByteMap::new();
+            let mut reference = BTreeMap::new();
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(map.insert(key, i as u32), reference.insert(key.clone(), i as u32));
+            }
+            for key in removed.iter() {
+                assert_eq!(map.remove(key), reference.remove(key));
+            }
+            assert_eq!(map.len(), reference.len());
+            assert!(map.iter().eq(reference.iter().map(|(k, v)| (k.as_slice(), v))));
+
+            // Taking two entries from the front for each from the back, the ends meet in the
+            // middle.
+            let mut iter = map.iter();
+            let mut expected = reference.iter().map(|(k, v)| (k.as_slice(), v));
+            for i in 0.. {
+                let entry = if i % 3 == 2 {
+                    (iter.next_back(), expected.next_back())
+                } else {
+                    (iter.next(), expected.next())
+                };
+                assert_eq!(entry.0, entry.1);
+                assert_eq!(iter.len(), expected.len());
+                if entry.0.is_none() {
+                    break;
+                }
+            }
+
+            for query in queries.iter() {
+                assert_eq!(map.get(query), reference.get(query));
+                assert_eq!(
+                    map.longest_prefix_match(query).map(|(k, _)| k.to_vec()),
+                    longest_prefix_match_reference(&reference, query)
+                );
+                assert!(map.prefix_iter(query).eq(reference
+                    .iter()
+                    .filter(|(k, _)| k.starts_with(query))
+                    .map(|(k, v)| (k.as_slice(), v))));
+                assert!(map.prefix_iter(query).rev().eq(reference
+                    .iter()
+                    .rev()
+                    .filter(|(k, _)| k.starts_with(query))
+                    .map(|(k, v)| (k.as_slice(), v))));
+            }
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::key::PatriciaKey;
+use alloc::vec::Vec;
+use core::iter::FusedIterator;
+use core::mem;
+use core::ops::{Bound, RangeBounds};
+use duplicate::duplicate_item;
+
+#[cfg(all(test, feature = "std"))]
+std::thread_local! {
+    /// The number of nodes opened by cursors on this thread.
+    pub(crate) static OPENED: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+/// Records that a cursor opens a node. Tests count these in `OPENED` to check how much of the
+/// tree an iterator visits; otherwise this does nothing.
+fn count_open() {
+    #[cfg(all(test, feature = "std"))]
+    OPENED.with(|opened| opened.set(opened.get() + 1));
+}
+
+/// Returns the smallest `k >= key` whose low `bits` bits are equal to those of `prefix`.
+pub(crate) fn ceil_with_prefix<K: PatriciaKey>(key: K, prefix: K, bits: u32) -> Option<K> {
+    let candidate = key.with_low_bits(prefix, bits);
+    if candidate >= key {
+        Some(candidate)
+    } else {
+        candidate.checked_add_bit(bits)
+    }
+}
+
+/// Returns the largest `k <= key` whose low `bits` bits are equal to those of `prefix`.
+pub(crate) fn floor_with_prefix<K: PatriciaKey>(key: K, prefix: K, bits: u32) -> Option<K> {
+    let candidate = key.with_low_bits(prefix, bits);
+    if candidate <= key {
+        Some(candidate)
+    } else {
+        candidate.checked_sub_bit(bits)
+    }
+}
+
+/// Converts `range` to inclusive bounds. An empty range gives a start greater than the end.
+pub(crate) fn inclusive_bounds<K: PatriciaKey>(range: impl RangeBounds<K>) -> (K, K) {
+    let start = match range.start_bound() {
+        Bound::Included(&start) => start,
+        Bound::Excluded(&start) => match start.checked_add_bit(0) {
+            Some(start) => start,
+            None => return (K::MAX, K::MIN),
+        },
+        Bound::Unbounded => K::MIN,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&end) => end,
+        Bound::Excluded(&end) => match end.checked_sub_bit(0) {
+            Some(end) => end,
+            None => return (K::MAX, K::MIN),
+        },
+        Bound::Unbounded => K::MAX,
+    };
+    (start, end)
+}
+
+/// The nodes of a tree as seen by a [`Cursor`], through handles of type `Self::Node`: references,
+/// indices or the nodes themselves.
+pub(crate) trait Tree<K> {
+    type Node;
+    type Entry;
+
+    /// Returns the low bits shared by all keys below `node` and how many bits that is, which is
+    /// `K::BITS` for a leaf and only for a leaf.
+    fn prefix(&self, node: &Self::Node) -> (K, u32);
+
+    /// Turns a leaf into its entry, or an internal node into its left and right children.
+    fn open(&mut self, node: Self::Node) -> Result<Self::Entry, [Self::Node; 2]>;
+}
+
+/// A priority queue of slots by key that pops the smallest key first, or the largest if
+/// `descending`, for keys that never come before the last key popped: a radix heap.
+///
+/// Keys are kept in buckets by the number of low bits in which they differ from the last key
+/// popped. A push only appends to a bucket. A pop from an empty first bucket takes the next
+/// bucket and makes its closest key the last one. The other keys in that bucket share more high
+/// bits with it than with the old last key, so each moves to a lower bucket, at most `K::BITS`
+/// times in all.
+struct Queue<K> {
+    descending: bool,
+    last: K,
+    buckets: Vec<Vec<(K, usize)>>,
+}
+
+impl<K: PatriciaKey> Queue<K> {
+    fn new(descending: bool, last: K) -> Self {
+        Self {
+            descending,
+            last,
+            buckets: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, key: K, slot: usize) {
+        let bucket = key.differing_bits(self.last) as usize;
+        if bucket >= self.buckets.len() {
+            self.buckets.resize_with(bucket + 1, Vec::new);
+        }
+        self.buckets[bucket].push((key, slot));
+    }
+
+    fn pop(&mut self) -> Option<(K, usize)> {
+        if self.buckets.first().is_none_or(Vec::is_empty) {
+            let index = self.buckets.iter().position(|bucket| !bucket.is_empty())?;
+            let mut bucket = mem::take(&mut self.buckets[index]);
+            let keys = bucket.iter().map(|&(key, _)| key);
+            self.last = if self.descending {
+                keys.max()
+            } else {
+                keys.min()
+            }
+            .unwrap();
+            for (key, slot) in bucket.drain(..) {
+                self.push(key, slot);
+            }
+            // Keep the allocation for keys that land in this bucket later.
+            self.buckets[index] = bucket;
+        }
+        self.buckets[0].pop()
+    }
+}
+
+/// Yields the entries of a tree with keys in an inclusive range, in ascending key order from the
+/// front and descending key order from the back, opening nodes only as they are needed.
+///
+/// Nodes branch on the lowest differing bit, so the keys below a node are spread over the whole
+/// key space and no traversal of the tree visits them in numeric order. Instead, each end keeps
+/// the nodes that have been reached but not opened in a queue, ordered by the closest key in range
+/// that each could hold, and opens the closest one next: a best-first search that resumes where
+/// the last entry was found. Nodes that cannot hold a key in the remaining range are never
+/// opened.
+///
+/// Taking the first few entries therefore opens only the nodes whose prefix admits a key before
+/// them. From the front, that is about the depth of the tree per entry when the keys are dense in
+/// their low bits. Nodes do not constrain the high bits of their keys, though, so it can be most
+/// of the tree for sparse keys, which differ in their high bits, and from the back of a range
+/// that extends far beyond the largest key. Taking all entries costs about as much as sorting
+/// them.
+pub(crate) struct Cursor<T, N, K> {
+    tree: T,
+    /// The nodes that have been reached but not opened, in slots shared by both ends.
+    nodes: Vec<Option<N>>,
+    /// Vacant slots in `nodes`.
+    vacant: Vec<usize>,
+    /// The occupied slots by the smallest key in range their node could hold, and by the largest.
+    /// Each queue is only built once its end is first used, and an entry may outlive its node
+    /// when the other end opens it first.
+    front: Option<Queue<K>>,
+    back: Option<Queue<K>>,
+    /// The keys that neither end has passed yet, or `None` once the ends have met.
+    range: Option<(K, K)>,
+    /// Bounds on the number of entries left.
+    len: (usize, usize),
+}
+
+impl<T: Tree<K, Node = N>, N, K: PatriciaKey> Cursor<T, N, K> {
+    /// Creates a cursor over the entries below `root` with keys in `start..=end`, of which there
+    /// are between `len.0` and `len.1`.
+    pub(crate) fn new(tree: T, root: Option<N>, (start, end): (K, K), len: (usize, usize)) -> Self {
+        let mut cursor = Self {
+            tree,
+            nodes: Vec::new(),
+            vacant: Vec::new(),
+            front: None,
+            back: None,
+            range: (start <= end).then_some((start, end)),
+            len,
+        };
+        if let Some(root) = root {
+            cursor.push(root);
+        }
+        cursor
+    }
+
+    /// Returns the smallest (`first_key`) or largest (`last_key`) key in the remaining range
+    /// that `node` could hold.
+    #[duplicate_item(
+      method      with_prefix          start(lo, hi);
+      [first_key] [ceil_with_prefix]   [lo];
+      [last_key]  [floor_with_prefix]  [hi];
+    )]
+    fn method(&self, node: &N) -> Option<K> {
+        let (lo, hi) = self.range?;
+        let (prefix, bits) = self.tree.prefix(node);
+        with_prefix(start([lo], [hi]), prefix, bits).filter(|key| lo <= *key && *key <= hi)
+    }
+
+    /// Keeps `node` for later if it could hold a key in the remaining range, and drops it
+    /// otherwise.
+    fn push(&mut self, node: N) {
+        let (Some(first), Some(last)) = (self.first_key(&node), self.last_key(&node)) else {
+            return;
+        };
+        let slot = match self.vacant.pop() {
+            Some(slot) => {
+                self.nodes[slot] = Some(node);
+                slot
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        if let Some(front) = &mut self.front {
+            front.push(first, slot);
+        }
+        if let Some(back) = &mut self.back {
+            back.push(last, slot);
+        }
+    }
+
+    /// Returns the entry with the smallest (`take_first`) or largest (`take_last`) key in the
+    /// remaining range, opening the nodes that could hold a key before it.
+    #[duplicate_item(
+      method       this    other   descending  bound_key    start  end    past  rest(key, range);
+      [take_first] [front] [back]  [false]     [first_key]  [0]    [1]    [gt]  [(key.checked_add_bit(0).unwrap(), range.1)];
+      [take_last]  [back]  [front] [true]      [last_key]   [1]    [0]    [lt]  [(range.0, key.checked_sub_bit(0).unwrap())];
+    )]
+    fn method(&mut self) -> Option<T::Entry> {
+        if self.this.is_none() {
+            let mut queue = Queue::new(descending, self.range?.start);
+            for (slot, node) in self.nodes.iter().enumerate() {
+                if let Some(bound) = node.as_ref().and_then(|node| self.bound_key(node)) {
+                    queue.push(bound, slot);
+                }
+            }
+            self.this = Some(queue);
+        }
+        loop {
+            let range = self.range?;
+            let queue = self.this.as_mut().unwrap();
+            let Some((bound, slot)) = queue.pop().filter(|&(bound, _)| !bound.past(&range.end))
+            else {
+                self.range = None;
+                return None;
+            };
+            let Some(node) = self.nodes[slot].take() else {
+                continue;
+            };
+            // The other queue may still refer to the slot, so it can only be reused without one.
+            if self.other.is_none() {
+                self.vacant.push(slot);
+            }
+            count_open();
+            match self.tree.open(node) {
+                // The closest key a leaf could hold is its own, so the range continues past it.
+                Ok(entry) => {
+                    self.range = (bound != range.end).then(|| rest([bound], [range]));
+                    self.len = (self.len.0.saturating_sub(1), self.len.1 - 1);
+                    return Some(entry);
+                }
+                Err(children) => children.into_iter().for_each(|child| self.push(child)),
+            }
+        }
+    }
+}
+
+impl<T, N, K> Cursor<T, N, K> {
+    /// Takes out the nodes that have not been opened, for the owner to drop without recursion.
+    pub(crate) fn take_nodes(&mut self) -> impl Iterator<Item = N> + '_ {
+        self.range = None;
+        self.nodes.drain(..).flatten()
+    }
+}
+
+impl<T: Tree<K, Node = N>, N, K: PatriciaKey> Iterator for Cursor<T, N, K> {
+    type Item = T::Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.take_first()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.range {
+            Some(_) => (self.len.0, Some(self.len.1)),
+            None => (0, Some(0)),
+        }
+    }
+}
+
+impl<T: Tree<K, Node = N>, N, K: PatriciaKey> DoubleEndedIterator for Cursor<T, N, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.take_last()
+    }
+}
+
+impl<T: Tree<K, Node = N>, N, K: PatriciaKey> FusedIterator for Cursor<T, N, K> {}
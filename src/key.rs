@@ -0,0 +1,91 @@
+use core::fmt::Debug;
+use core::hash::Hash;
+use duplicate::duplicate_item;
+
+/// An unsigned integer type usable as the key of a [`PatriciaTreeMap`].
+///
+/// The tree only ever looks at the bits of its keys: nodes branch on the lowest bit in which two
+/// keys differ and store the low bits that all keys below them share.
+///
+/// [`PatriciaTreeMap`]: crate::map::PatriciaTreeMap
+pub trait PatriciaKey: Copy + Ord + Hash + Debug {
+    /// The number of bits in a key.
+    const BITS: u32;
+    /// The smallest key.
+    const MIN: Self;
+    /// The largest key.
+    const MAX: Self;
+
+    /// Returns the low `bits` bits of `self`, where any `bits >= Self::BITS` selects the entire
+    /// key.
+    fn low_bits(self, bits: u32) -> Self;
+
+    /// Returns whether bit `bit` of `self` is set, where any `bit >= Self::BITS` is unset.
+    fn bit(self, bit: u32) -> bool;
+
+    /// Returns the lowest bit in which `self` and `other` differ, or `Self::BITS` if they are
+    /// equal.
+    fn branch_bit(self, other: Self) -> u32;
+
+    /// Returns the number of low bits up to and including the highest set bit.
+    fn significant_bits(self) -> u32;
+
+    /// Returns the number of low bits up to and including the highest bit in which `self` and
+    /// `other` differ, or 0 if they are equal.
+    fn differing_bits(self, other: Self) -> u32;
+
+    /// Returns `self` with its low `bits` bits replaced by those of `low`.
+    fn with_low_bits(self, low: Self, bits: u32) -> Self;
+
+    /// Returns `self + 2^bits`, or `None` if that does not fit in a key.
+    fn checked_add_bit(self, bits: u32) -> Option<Self>;
+
+    /// Returns `self - 2^bits`, or `None` if that does not fit in a key.
+    fn checked_sub_bit(self, bits: u32) -> Option<Self>;
+
+    /// Returns the absolute difference between `self` and `other`.
+    fn abs_diff(self, other: Self) -> Self;
+}
+
+#[duplicate_item(int; [u32]; [u64]; [u128];)]
+impl PatriciaKey for int {
+    const BITS: u32 = int::BITS;
+    const MIN: Self = int::MIN;
+    const MAX: Self = int::MAX;
+
+    fn low_bits(self, bits: u32) -> Self {
+        self & int::checked_shl(1, bits).map_or(int::MAX, |bit| bit - 1)
+    }
+
+    fn bit(self, bit: u32) -> bool {
+        int::checked_shr(self, bit).is_some_and(|shifted| shifted & 1 == 1)
+    }
+
+    fn branch_bit(self, other: Self) -> u32 {
+        (self ^ other).trailing_zeros()
+    }
+
+    fn significant_bits(self) -> u32 {
+        int::BITS - self.leading_zeros()
+    }
+
+    fn differing_bits(self, other: Self) -> u32 {
+        (self ^ other).significant_bits()
+    }
+
+    fn with_low_bits(self, low: Self, bits: u32) -> Self {
+        (self ^ self.low_bits(bits)) | low.low_bits(bits)
+    }
+
+    fn checked_add_bit(self, bits: u32) -> Option<Self> {
+        int::checked_shl(1, bits).and_then(|step| self.checked_add(step))
+    }
+
+    fn checked_sub_bit(self, bits: u32) -> Option<Self> {
+        int::checked_shl(1, bits).and_then(|step| self.checked_sub(step))
+    }
+
+    fn abs_diff(self, other: Self) -> Self {
+        int::abs_diff(self, other)
+    }
+}
@@ -1,2 +1,71 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod arena_map;
+pub mod byte_map;
+mod cursor;
+pub mod key;
 pub mod map;
+pub mod persistent_map;
+pub mod routing;
 pub mod set;
+
+#[cfg(feature = "std")]
+use replace_with::replace_with_or_abort;
+
+/// Creates a [`PatriciaTreeMap`](map::PatriciaTreeMap) with `u64` keys from `key => value`
+/// pairs, where later values replace earlier ones with the same key.
+#[macro_export]
+macro_rules! patricia_map {
+    ($($key:expr => $value:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut map = $crate::map::PatriciaTreeMap::new();
+        $(map.insert($key, $value);)*
+        map
+    }};
+}
+
+/// Creates a [`PatriciaTreeSet`](set::PatriciaTreeSet) from keys.
+#[macro_export]
+macro_rules! patricia_set {
+    ($($key:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut set = $crate::set::PatriciaTreeSet::new();
+        $(set.insert($key);)*
+        set
+    }};
+}
+
+/// `replace_with` only provides `replace_with_or_abort` with `std`. Without it, panicking again
+/// while `f` unwinds aborts just the same.
+#[cfg(not(feature = "std"))]
+fn replace_with_or_abort<T, F: FnOnce(T) -> T>(dest: &mut T, f: F) {
+    replace_with::replace_with(dest, || panic!("panicked while restructuring the tree"), f)
+}
+
+/// Exercises the maps with nothing but `core` and `alloc`, as on a `no_std` target. Run with
+/// `cargo test --no-default-features`.
+#[cfg(all(test, not(feature = "std")))]
+mod test {
+    use crate::byte_map::PatriciaByteMap;
+    use crate::map::PatriciaTreeMap;
+    use crate::set::PatriciaTreeSet;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_no_std() {
+        let mut map: PatriciaTreeMap<u64> = (0..100).map(|key| (key * 37, key)).collect();
+        assert_eq!(map.remove(37), Some(1));
+        map.retain(|key, _| key % 2 == 0);
+        assert_eq!(map.len(), 50);
+        assert_eq!(map.keys().take(3).collect::<Vec<_>>(), [0, 74, 148]);
+
+        let mut set = PatriciaTreeSet::new();
+        assert!(set.insert(5));
+        assert!(set.contains(5));
+
+        let map: PatriciaByteMap<u32> = [("cat", 1), ("car", 2)].into_iter().collect();
+        assert_eq!(map.longest_prefix_match("cart"), Some((&b"car"[..], &2)));
+    }
+}
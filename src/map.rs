@@ -1,38 +1,117 @@
-use duplicate::duplicate_item;
-use replace_with::replace_with_or_abort;
-use std::mem;
+use crate::cursor::{ceil_with_prefix, floor_with_prefix, inclusive_bounds, Cursor, Tree};
+use crate::key::PatriciaKey;
+use crate::replace_with_or_abort;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::{self, Vec};
+use core::cmp::Ordering;
+use core::fmt::{self, Debug, Display, Write};
+use core::hash::{Hash, Hasher};
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Index, RangeBounds};
+use duplicate::{duplicate, duplicate_item};
 
-#[derive(Debug)]
-struct LeafNode<V> {
-    key: u64,
+#[derive(Clone, Debug)]
+struct LeafNode<V, K> {
+    key: K,
     value: V,
 }
 
-#[derive(Debug)]
-struct InternalNode<V> {
-    key_prefix: u64,
+/// The children of an internal node share a single allocation, so a leaf is stored inline in
+/// its parent rather than boxed on its own.
+#[derive(Clone, Debug)]
+struct InternalNode<V, K> {
+    key_prefix: K,
     branch_bit: u8,
-    left: Box<Node<V>>,
-    right: Box<Node<V>>,
+    children: Box<(Node<V, K>, Node<V, K>)>,
 }
 
 #[derive(Debug)]
-enum Node<V> {
-    Leaf(LeafNode<V>),
-    Internal(InternalNode<V>),
+enum Node<V, K> {
+    Leaf(LeafNode<V, K>),
+    Internal(InternalNode<V, K>),
 }
 
-#[derive(Debug)]
-pub struct PatriciaTreeMap<V> {
+impl<V: Clone, K: Clone> Clone for Node<V, K> {
+    fn clone(&self) -> Self {
+        match self {
+            Node::Leaf(leaf) => Node::Leaf(leaf.clone()),
+            Node::Internal(internal) => Node::Internal(internal.clone()),
+        }
+    }
+
+    /// Reuses the allocations and values of `self` wherever it has the same shape as `source`.
+    fn clone_from(&mut self, source: &Self) {
+        match (self, source) {
+            (Node::Leaf(leaf), Node::Leaf(source)) => {
+                leaf.key.clone_from(&source.key);
+                leaf.value.clone_from(&source.value);
+            }
+            (Node::Internal(internal), Node::Internal(source)) => {
+                internal.key_prefix.clone_from(&source.key_prefix);
+                internal.branch_bit = source.branch_bit;
+                internal.children.0.clone_from(&source.children.0);
+                internal.children.1.clone_from(&source.children.1);
+            }
+            (node, source) => *node = source.clone(),
+        }
+    }
+}
+
+/// The nodes of a [`PatriciaTreeMap`] flattened into parallel arrays in pre-order, created by
+/// [`PatriciaTreeMap::to_flat`] and turned back into a map by [`PatriciaTreeMap::from_flat`].
+///
+/// Node `i` is a leaf if `is_leaf[i]`, with key `prefixes[i]`. Otherwise it is an internal node
+/// with key prefix `prefixes[i]` and branch bit `branch_bits[i]`, whose left child is node
+/// `i + 1` and whose right child is node `right_children[i]`. The values of the leaves are in
+/// `values` in the order the leaves appear.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlatPatricia<V, K = u64> {
+    pub is_leaf: Vec<bool>,
+    pub branch_bits: Vec<u8>,
+    pub prefixes: Vec<K>,
+    pub right_children: Vec<u32>,
+    pub values: Vec<V>,
+}
+
+#[cfg(all(test, feature = "std"))]
+std::thread_local! {
+    /// The number of times a key has been looked up from the root on this thread.
+    static DESCENTS: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+/// Records a lookup from the root. Tests count these in `DESCENTS` to check how often an
+/// operation descends the tree; otherwise this does nothing.
+fn count_descent() {
+    #[cfg(all(test, feature = "std"))]
+    DESCENTS.with(|descents| descents.set(descents.get() + 1));
+}
+
+/// A map from integer keys to values of type `V`, stored in a Patricia tree.
+///
+/// Keys are `u64` by default; any [`PatriciaKey`], such as `u32` or `u128`, can be used instead.
+pub struct PatriciaTreeMap<V, K = u64> {
     size: usize,
-    root: Option<Box<Node<V>>>,
+    root: Option<Node<V, K>>,
 }
 
 impl<V> PatriciaTreeMap<V> {
+    /// Creates an empty map with `u64` keys. Maps with other key types are created with
+    /// [`Default::default`].
     pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<V, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    /// Creates a map holding just `key` mapped to `value`.
+    pub fn singleton(key: K, value: V) -> Self {
         Self {
-            size: 0,
-            root: None,
+            size: 1,
+            root: Some(Node::Leaf(LeafNode { key, value })),
         }
     }
 
@@ -44,13 +123,22 @@ impl<V> PatriciaTreeMap<V> {
         self.len() == 0
     }
 
-    fn get_prefix(key: u64, branch_bit: u8) -> u64 {
-        let mask = (1 << branch_bit) - 1;
-        key & mask
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.size = 0;
+    }
+
+    /// Returns the bits of `key` below `branch_bit`. Branch bits are below `K::BITS`, but
+    /// `branch_bit == K::BITS`, as for two equal keys, selects the entire key.
+    fn get_prefix(key: K, branch_bit: u8) -> K {
+        key.low_bits(branch_bit as u32)
     }
 
-    fn is_left(key: u64, branch_bit: u8) -> bool {
-        key & (1 << branch_bit) == 0
+    /// Returns whether `key` belongs in the left subtree of a node branching on `branch_bit`.
+    /// Like [`get_prefix`](Self::get_prefix), this accepts `branch_bit == K::BITS`, where every
+    /// key is on the left.
+    fn is_left(key: K, branch_bit: u8) -> bool {
+        !key.bit(branch_bit as u32)
     }
 
     #[duplicate_item(
@@ -59,294 +147,4565 @@ impl<V> PatriciaTreeMap<V> {
       [find_insertion_point_mut] [&mut type]     [v.as_mut()];
     )]
     #[allow(clippy::needless_arbitrary_self_type)]
-    fn method(self: reference([Self]), key: u64) -> Option<reference([Node<V>])> {
-        fn aux<V>(node: reference([Node<V>]), key: u64) -> reference([Node<V>]) {
-            match node {
-                Node::Leaf { .. } => node,
+    fn method(self: reference([Self]), key: K) -> Option<reference([Node<V, K>])> {
+        count_descent();
+        let mut node = as_ref([self.root])?;
+        loop {
+            let is_left = match node {
                 Node::Internal(InternalNode {
                     key_prefix,
                     branch_bit,
                     ..
-                }) if *key_prefix != PatriciaTreeMap::<V>::get_prefix(key, *branch_bit) => node,
-                Node::Internal(InternalNode {
-                    branch_bit,
-                    right,
-                    left,
-                    ..
-                }) => {
-                    if PatriciaTreeMap::<V>::is_left(key, *branch_bit) {
-                        aux(left, key)
+                }) if *key_prefix == Self::get_prefix(key, *branch_bit) => {
+                    Self::is_left(key, *branch_bit)
+                }
+                _ => return Some(node),
+            };
+            node = match node {
+                Node::Internal(InternalNode { children, .. }) => {
+                    let (left, right) = reference([**children]);
+                    if is_left {
+                        left
                     } else {
-                        aux(right, key)
+                        right
                     }
                 }
-            }
+                Node::Leaf { .. } => unreachable!(),
+            };
         }
-
-        as_ref([self.root]).map(|r| aux(r, key))
     }
 
-    pub fn get(&self, key: u64) -> Option<&V> {
+    pub fn get(&self, key: K) -> Option<&V> {
         match self.find_insertion_point(key) {
             Some(Node::Leaf(LeafNode { key: k, value: v })) if k == &key => Some(v),
             _ => None,
         }
     }
 
-    pub fn contains(&self, key: u64) -> bool {
-        self.get(key).is_some()
+    pub fn get_key_value(&self, key: K) -> Option<(K, &V)> {
+        match self.find_insertion_point(key) {
+            Some(Node::Leaf(LeafNode { key: k, value: v })) if k == &key => Some((*k, v)),
+            _ => None,
+        }
     }
 
-    pub fn insert(&mut self, key: u64, value: V) -> Option<V> {
-        fn aux<V>(tree: &mut PatriciaTreeMap<V>, key: u64, value: V) -> Option<V> {
-            fn do_insert<V>(diff: u64, key: u64, value: V, node: &mut Node<V>) -> Option<V> {
-                let branch_bit = diff.trailing_zeros() as u8;
-                let key_prefix = PatriciaTreeMap::<V>::get_prefix(key, branch_bit);
+    pub fn get_mut(&mut self, key: K) -> Option<&mut V> {
+        match self.find_insertion_point_mut(key) {
+            Some(Node::Leaf(LeafNode { key: k, value: v })) if k == &key => Some(v),
+            _ => None,
+        }
+    }
 
-                let leaf = Node::Leaf(LeafNode { key, value });
-                replace_with_or_abort(node, |old_node| {
-                    let (left, right) = if PatriciaTreeMap::<V>::is_left(key, branch_bit) {
-                        (leaf, old_node)
-                    } else {
-                        (old_node, leaf)
-                    };
+    /// Returns whether `key` is in the map.
+    pub fn contains_key(&self, key: K) -> bool {
+        self.get(key).is_some()
+    }
 
-                    Node::Internal(InternalNode {
-                        branch_bit,
-                        key_prefix,
-                        left: Box::new(left),
-                        right: Box::new(right),
-                    })
-                });
+    #[deprecated(note = "renamed to `contains_key`, like `BTreeMap::contains_key`")]
+    pub fn contains(&self, key: K) -> bool {
+        self.contains_key(key)
+    }
 
-                None
+    /// Returns mutable references to the values of all `keys` at once, or `None` for the keys
+    /// that are not in the map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `keys` are not all distinct.
+    pub fn get_disjoint_mut<const N: usize>(&mut self, keys: [K; N]) -> [Option<&mut V>; N] {
+        /// Looks up the `(index, key)` pairs of `requests` below `node`. The two children of a
+        /// node are borrowed separately, so the references stay disjoint.
+        fn aux<'a, V, K: PatriciaKey>(
+            node: &'a mut Node<V, K>,
+            requests: Vec<(usize, K)>,
+            res: &mut [Option<&'a mut V>],
+        ) {
+            match node {
+                Node::Leaf(LeafNode { key, value }) => {
+                    if let Some(&(index, _)) = requests.iter().find(|(_, k)| k == key) {
+                        res[index] = Some(value);
+                    }
+                }
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children,
+                }) => {
+                    let (left, right) = &mut **children;
+                    let (left_requests, right_requests): (Vec<_>, Vec<_>) = requests
+                        .into_iter()
+                        .filter(|(_, key)| {
+                            *key_prefix == PatriciaTreeMap::<V, K>::get_prefix(*key, *branch_bit)
+                        })
+                        .partition(|(_, key)| PatriciaTreeMap::<V, K>::is_left(*key, *branch_bit));
+                    if !left_requests.is_empty() {
+                        aux(left, left_requests, res);
+                    }
+                    if !right_requests.is_empty() {
+                        aux(right, right_requests, res);
+                    }
+                }
             }
+        }
 
-            let node = tree.find_insertion_point_mut(key);
+        for (i, key) in keys.iter().enumerate() {
+            assert!(!keys[..i].contains(key), "duplicate key {:?}", key);
+        }
+        let mut res = core::array::from_fn(|_| None);
+        if let Some(root) = self.root.as_mut() {
+            aux(root, keys.into_iter().enumerate().collect(), &mut res);
+        }
+        res
+    }
+
+    /// Returns the values of `keys` in the same order, or `None` for the keys that are not in the
+    /// map. Keys that share a path from the root are looked up together, so the path is only
+    /// walked once.
+    pub fn get_many<'a>(&'a self, keys: &[K]) -> Vec<Option<&'a V>> {
+        /// Looks up the `(index, key)` pairs of `requests` below `node`.
+        fn aux<'a, V, K: PatriciaKey>(
+            node: &'a Node<V, K>,
+            requests: Vec<(usize, K)>,
+            res: &mut [Option<&'a V>],
+        ) {
             match node {
-                None => {
-                    tree.root = Some(Box::new(Node::Leaf(LeafNode { key, value })));
-                    None
+                Node::Leaf(LeafNode { key, value }) => {
+                    for (index, _) in requests.into_iter().filter(|(_, k)| k == key) {
+                        res[index] = Some(value);
+                    }
                 }
-                Some(node) => match node {
-                    Node::Leaf(LeafNode { key: k, value: v }) => {
-                        if k != &key {
-                            let diff = *k ^ key;
-                            do_insert(diff, key, value, node)
-                        } else {
-                            Some(mem::replace(v, value))
-                        }
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children,
+                }) => {
+                    let (left, right) = &**children;
+                    let (left_requests, right_requests): (Vec<_>, Vec<_>) = requests
+                        .into_iter()
+                        .filter(|(_, key)| {
+                            *key_prefix == PatriciaTreeMap::<V, K>::get_prefix(*key, *branch_bit)
+                        })
+                        .partition(|(_, key)| PatriciaTreeMap::<V, K>::is_left(*key, *branch_bit));
+                    if !left_requests.is_empty() {
+                        aux(left, left_requests, res);
                     }
-                    Node::Internal(InternalNode { key_prefix, .. }) => {
-                        let diff = *key_prefix ^ key;
-                        do_insert(diff, key, value, node)
+                    if !right_requests.is_empty() {
+                        aux(right, right_requests, res);
                     }
-                },
+                }
             }
         }
 
-        let res = aux(self, key, value);
-        self.size += res.is_none() as usize;
+        let mut res = alloc::vec![None; keys.len()];
+        if let Some(root) = self.root.as_ref() {
+            aux(root, keys.iter().copied().enumerate().collect(), &mut res);
+        }
         res
     }
-}
 
-impl<V> Default for PatriciaTreeMap<V> {
-    fn default() -> Self {
-        Self::new()
+    /// Swaps the values of `a` and `b`, returning whether both keys are in the map. The map is
+    /// left unchanged if either key is missing.
+    pub fn swap_values(&mut self, a: K, b: K) -> bool {
+        if a == b {
+            return self.contains_key(a);
+        }
+        match self.get_disjoint_mut([a, b]) {
+            [Some(a), Some(b)] => {
+                mem::swap(a, b);
+                true
+            }
+            _ => false,
+        }
     }
-}
 
-pub struct PatriciaTreeMapIterator<'a, V> {
-    map: &'a PatriciaTreeMap<V>,
-    path: Vec<&'a InternalNode<V>>,
-    last_was_left: bool,
-}
+    /// Returns whether `prefix` is a prefix of `key`, i.e. whether the low bits of `key`, up to
+    /// and including the highest set bit of `prefix`, are equal to `prefix`.
+    fn is_prefix_of(prefix: K, key: K) -> bool {
+        prefix.branch_bit(key) >= prefix.significant_bits()
+    }
 
-impl<'a, V> PatriciaTreeMapIterator<'a, V> {
-    fn new(map: &'a PatriciaTreeMap<V>) -> Self {
-        let path = vec![];
-        Self {
-            map,
-            path,
-            last_was_left: true,
+    /// Returns the stored entry whose key is the longest prefix of `key`.
+    ///
+    /// As the tree branches on the lowest bits first, prefixes are taken from the low-order end:
+    /// a stored key `k` is a prefix of `key` if `key` agrees with `k` on every bit up to and
+    /// including the highest set bit of `k`. In particular the key `0` is a prefix of every key.
+    pub fn longest_prefix_match(&self, key: K) -> Option<(K, &V)> {
+        // A stored prefix of `key` follows the path to `key` up to its highest set bit, and only
+        // takes left turns after that. So it is the leftmost leaf either of the subtree where
+        // that path ends, or of a left sibling passed on the way, and deeper ones are longer.
+        let mut res = None;
+        let mut node = self.root.as_ref();
+        while let Some(n) = node {
+            match n {
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children,
+                }) if *key_prefix == Self::get_prefix(key, *branch_bit) => {
+                    let (left, right) = &**children;
+                    node = Some(if Self::is_left(key, *branch_bit) {
+                        left
+                    } else {
+                        res = Self::leftmost_prefix_of(left, key).or(res);
+                        right
+                    });
+                }
+                _ => {
+                    res = Self::leftmost_prefix_of(n, key).or(res);
+                    break;
+                }
+            }
         }
+        res
     }
 
-    fn find_leftmost(&mut self, node: &'a Node<V>) -> Option<(u64, &'a V)> {
-        self.last_was_left = false;
-        let mut node = node;
+    /// Returns the leftmost entry below `node` if its key is a prefix of `key`, giving up as soon
+    /// as the prefix of a node on the way rules that out.
+    fn leftmost_prefix_of(mut node: &Node<V, K>, key: K) -> Option<(K, &V)> {
         loop {
             match node {
-                Node::Leaf(LeafNode { key, value }) => {
-                    break Some((*key, value));
+                Node::Leaf(LeafNode { key: k, value }) => {
+                    return Self::is_prefix_of(*k, key).then_some((*k, value));
                 }
-                Node::Internal(internal_node) => {
-                    self.path.push(internal_node);
-                    self.last_was_left = true;
-                    node = &internal_node.left;
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    children,
+                    ..
+                }) => {
+                    if !Self::is_prefix_of(*key_prefix, key) {
+                        return None;
+                    }
+                    node = &children.0;
                 }
             }
         }
     }
-}
 
-impl<'a, V> Iterator for PatriciaTreeMapIterator<'a, V> {
-    type Item = (u64, &'a V);
-    
-    fn next(&mut self) -> Option<Self::Item> {
-        let prev_parent = self.path.pop();
-        match prev_parent {
-            None => {
-                match &self.map.root {
-                    None => None,
-                    Some(node) => {
-                        if self.last_was_left {
-                            self.find_leftmost(node)
-                        } else {
-                            debug_assert_eq!(self.map.len(), 1);
-                            self.last_was_left = true;
-                            None
-                        }
+    /// Like [`longest_prefix_match`](Self::longest_prefix_match), but also returns the length
+    /// of the matched prefix, i.e. the number of low bits of `key` that the stored key covers.
+    pub fn longest_prefix_match_len(&self, key: K) -> Option<(K, u8, &V)> {
+        self.longest_prefix_match(key)
+            .map(|(k, value)| (k, k.significant_bits() as u8, value))
+    }
+
+    /// Returns an iterator over the stored entries whose keys are prefixes of the low `key_len`
+    /// bits of `key`, in the sense of [`longest_prefix_match`](Self::longest_prefix_match), from
+    /// the shortest prefix to the longest.
+    pub fn ancestors(&self, key: K, key_len: u8) -> impl Iterator<Item = (K, &V)> + '_ {
+        let key = key.low_bits(key_len as u32);
+        // Only the lengths ending in a set bit give distinct prefixes.
+        (0..=key.significant_bits())
+            .filter(move |&len| len == 0 || key.bit(len - 1))
+            .filter_map(move |len| {
+                let prefix = key.low_bits(len);
+                self.get(prefix).map(|value| (prefix, value))
+            })
+    }
+
+    /// Returns an iterator over the stored entries whose keys have `prefix` as their low
+    /// `prefix_len` bits, the counterpart of [`ancestors`](Self::ancestors).
+    ///
+    /// This descends directly to the subtree holding these keys, just like
+    /// [`prefix_iter`](Self::prefix_iter), and likewise yields them in ascending key order.
+    pub fn descendants(&self, prefix: K, prefix_len: u8) -> impl Iterator<Item = (K, &V)> + '_ {
+        self.prefix_iter(prefix, prefix_len)
+    }
+
+    /// Returns the entry with the smallest key `>= key` (`ceiling`) or the largest key `<= key`
+    /// (`floor`).
+    ///
+    /// Numeric order does not follow the tree layout, so this is a branch-and-bound search: a
+    /// subtree is only visited if the closest key it could possibly contain, given its
+    /// `key_prefix`, beats the best entry found so far.
+    #[duplicate_item(
+      method    with_prefix          better(a, b);
+      [ceiling] [ceil_with_prefix]   [a < b];
+      [floor]   [floor_with_prefix]  [a > b];
+    )]
+    pub fn method(&self, key: K) -> Option<(K, &V)> {
+        fn bound<V, K: PatriciaKey>(node: &Node<V, K>, key: K) -> Option<K> {
+            match node {
+                Node::Leaf(LeafNode { key: k, .. }) => with_prefix(key, *k, K::BITS),
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    ..
+                }) => with_prefix(key, *key_prefix, *branch_bit as u32),
+            }
+        }
+
+        fn improves<K: PatriciaKey>(bound: Option<K>, best: Option<K>) -> bool {
+            match (bound, best) {
+                (None, _) => false,
+                (Some(_), None) => true,
+                (Some(bound), Some(best)) => better([bound], [best]),
+            }
+        }
+
+        fn aux<'a, V, K: PatriciaKey>(node: &'a Node<V, K>, key: K, res: &mut Option<(K, &'a V)>) {
+            match node {
+                Node::Leaf(LeafNode { key: k, value }) => {
+                    if improves(bound(node, key), res.map(|(k, _)| k)) {
+                        *res = Some((*k, value));
                     }
                 }
-            },
-            Some(internal_node) => {
-                let mut internal_node = internal_node;
-                if !self.last_was_left {
-                    loop {
-                        match self.path.pop() {
-                            None => {
-                                self.last_was_left = true;
-                                return None;
-                            }
-                            Some(parent_node) => {
-                                let is_left = PatriciaTreeMap::<V>::is_left(internal_node.key_prefix, parent_node.branch_bit);
-                                internal_node = parent_node;
-                                if is_left {
-                                    break;
-                                }
-                            },
+                Node::Internal(InternalNode { children, .. }) => {
+                    let (left, right) = &**children;
+                    let children = if improves(bound(right, key), bound(left, key)) {
+                        [right, left]
+                    } else {
+                        [left, right]
+                    };
+                    for child in children {
+                        if improves(bound(child, key), res.map(|(k, _)| k)) {
+                            aux(child, key, res);
                         }
                     }
                 }
-
-                self.path.push(internal_node);
-                self.find_leftmost(&internal_node.right)
             }
         }
-    }
-}
 
-impl<V> PatriciaTreeMap<V> {
-    pub fn iter(&self) -> PatriciaTreeMapIterator<V> {
-        PatriciaTreeMapIterator::new(self)
+        let mut res = None;
+        if let Some(root) = self.root.as_ref() {
+            aux(root, key, &mut res);
+        }
+        res
     }
-}
-
-#[cfg(test)]
-mod test {
-    use super::PatriciaTreeMap;
-    use proptest::bits;
-    use proptest::collection::hash_set;
-    use proptest::collection::vec;
-    use proptest::collection::SizeRange;
-    use proptest::prelude::*;
-    use std::collections::BTreeMap;
-    use std::collections::HashMap;
-    use std::hash::Hash;
 
-    #[test]
-    fn test_empty_map() {
-        let map = PatriciaTreeMap::<String>::new();
-        assert_eq!(map.len(), 0);
-        assert_eq!(map.get(0), None);
-        assert_eq!(map.iter().next(), None);
+    /// Returns the entry with the smallest key.
+    pub fn min(&self) -> Option<(K, &V)> {
+        self.ceiling(K::MIN)
     }
 
-    #[test]
-    fn test_iter() {
-        let mut map = PatriciaTreeMap::<&'static str>::new();
-        assert_eq!(map.iter().next(), None);
+    /// Returns the entry with the largest key.
+    pub fn max(&self) -> Option<(K, &V)> {
+        self.floor(K::MAX)
+    }
 
-        map.insert(0b001, "B".into());
-        let mut iter = map.iter();
-        assert_eq!(iter.next(), Some((0b001, &"B")));
-        assert_eq!(iter.next(), None);
+    /// Returns the low bits shared by all keys in the map and how many bits that is, which is
+    /// all of them if the map holds a single key.
+    pub fn common_prefix(&self) -> Option<(K, u8)> {
+        let (prefix, bits) = self.root.as_ref()?.prefix();
+        Some((prefix, bits as u8))
+    }
 
-        map.insert(0b011, "C".into());
-        map.insert(0b010, "A".into());
-        let mut iter = map.iter();
-        assert_eq!(iter.next(), Some((0b010, &"A")));
-        assert_eq!(iter.next(), Some((0b001, &"B")));
-        assert_eq!(iter.next(), Some((0b011, &"C")));
-        assert_eq!(iter.next(), None);
+    /// Returns the entry with the smallest key, like [`BTreeMap::first_key_value`].
+    ///
+    /// [`BTreeMap::first_key_value`]: alloc::collections::BTreeMap::first_key_value
+    pub fn first_key_value(&self) -> Option<(K, &V)> {
+        self.min()
     }
 
-    #[test]
-    fn test_insert_return_value() {
-        let mut map = PatriciaTreeMap::<String>::new();
-        assert_eq!(map.get(123), None);
-        assert_eq!(map.insert(123, "A".into()), None);
-        assert_eq!(map.get(123), Some(&"A".into()));
-        assert_eq!(map.insert(123, "B".into()), Some("A".into()));
-        assert_eq!(map.get(123), Some(&"B".into()));
+    /// Returns the entry with the largest key, like [`BTreeMap::last_key_value`].
+    ///
+    /// [`BTreeMap::last_key_value`]: alloc::collections::BTreeMap::last_key_value
+    pub fn last_key_value(&self) -> Option<(K, &V)> {
+        self.max()
     }
 
-    fn unique_vec<T>(element: T, size: impl Into<SizeRange>) -> impl Strategy<Value = Vec<T::Value>>
-    where
-        T: Strategy,
-        T::Value: Hash + Eq,
-    {
-        let x = hash_set(element, size);
-        x.prop_map(|v| v.into_iter().collect())
+    /// Returns the entry whose key is numerically closest to `key`, preferring the smaller key
+    /// when two are equally close.
+    pub fn nearest(&self, key: K) -> Option<(K, &V)> {
+        match (self.floor(key), self.ceiling(key)) {
+            (Some(below), Some(above)) if above.0.abs_diff(key) < key.abs_diff(below.0) => {
+                Some(above)
+            }
+            (Some(below), _) => Some(below),
+            (None, above) => above,
+        }
     }
 
-    fn from_keys(keys: Vec<u64>) -> (PatriciaTreeMap<String>, BTreeMap<u64, String>) {
-        let mut tree = PatriciaTreeMap::<String>::new();
-        let mut counter = HashMap::<u64, usize>::new();
-        let mut reference = BTreeMap::<u64, String>::new();
-        for key in keys.into_iter() {
-            let count = counter.entry(key).or_insert(0);
-            *count += 1;
-            let value = format!("{}-{}", key, count);
-            tree.insert(key, value.clone());
-            reference.insert(key, value);
+    /// Returns the number of keys smaller than `key`.
+    ///
+    /// This takes `O(n)` time in the worst case. The keys below a node share their low bits
+    /// rather than forming a contiguous range, so a subtree is almost never entirely below or
+    /// entirely above `key`, and storing subtree sizes would not let the walk skip it. Instead,
+    /// the tree is walked, skipping only the subtrees whose smallest possible key, their prefix,
+    /// is not smaller than `key`.
+    pub fn rank(&self, key: K) -> usize {
+        let mut rank = 0;
+        let mut stack: Vec<&Node<V, K>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            match node {
+                Node::Leaf(LeafNode { key: k, .. }) => rank += (*k < key) as usize,
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    children,
+                    ..
+                }) => {
+                    if *key_prefix < key {
+                        let (left, right) = &**children;
+                        stack.push(right);
+                        stack.push(left);
+                    }
+                }
+            }
         }
-        (tree, reference)
+        rank
     }
 
-    fn test_insertion_impl(keys: Vec<u64>) {
-        let (tree, reference) = from_keys(keys);
+    /// Returns the entry with the `i`-th smallest key, counting from `0`.
+    ///
+    /// As with [`rank`](Self::rank), the tree does not order its keys numerically, so subtree
+    /// sizes cannot guide a descent to the `i`-th key. Instead, this selects from all entries in
+    /// expected `O(n)` time rather than sorting them.
+    pub fn select(&self, i: usize) -> Option<(K, &V)> {
+        if i >= self.len() {
+            return None;
+        }
+        let mut entries = Vec::with_capacity(self.len());
+        let mut stack: Vec<&Node<V, K>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            match node {
+                Node::Leaf(LeafNode { key, value }) => entries.push((*key, value)),
+                Node::Internal(InternalNode { children, .. }) => {
+                    let (left, right) = &**children;
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        let (_, entry, _) = entries.select_nth_unstable_by_key(i, |(key, _)| *key);
+        Some(*entry)
+    }
 
-        assert_eq!(tree.len(), reference.len());
+    /// Returns the number of keys in `range`, without collecting the entries like
+    /// [`range`](Self::range). Like that, it skips the subtrees without keys in the range, but
+    /// like [`rank`](Self::rank), it counts the leaves of the rest one by one.
+    pub fn count_range(&self, range: impl RangeBounds<K>) -> usize {
+        let (start, end) = inclusive_bounds(range);
+        let mut count = 0;
+        let mut stack: Vec<&Node<V, K>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            match node {
+                Node::Leaf(LeafNode { key, .. }) => {
+                    count += (start <= *key && *key <= end) as usize
+                }
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children,
+                }) => {
+                    let first = ceil_with_prefix(start, *key_prefix, *branch_bit as u32);
+                    if first.is_some_and(|first| first <= end) {
+                        let (left, right) = &**children;
+                        stack.push(right);
+                        stack.push(left);
+                    }
+                }
+            }
+        }
+        count
+    }
 
-        for (k, v) in reference.into_iter() {
-            assert_eq!(tree.get(k), Some(&v));
+    /// Returns the root of the subtree containing exactly the keys whose low `prefix_len` bits
+    /// are equal to those of `prefix`.
+    fn find_prefix_subtree(&self, prefix: K, prefix_len: u8) -> Option<&Node<V, K>> {
+        let mut node = self.root.as_ref()?;
+        loop {
+            match node {
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children,
+                }) if *branch_bit < prefix_len => {
+                    if *key_prefix != Self::get_prefix(prefix, *branch_bit) {
+                        return None;
+                    }
+                    let (left, right) = &**children;
+                    node = if Self::is_left(prefix, *branch_bit) {
+                        left
+                    } else {
+                        right
+                    };
+                }
+                // Every key below this node shares at least the low `prefix_len` bits.
+                Node::Internal(InternalNode {
+                    key_prefix: key, ..
+                })
+                | Node::Leaf(LeafNode { key, .. }) => {
+                    return (key.low_bits(prefix_len as u32) == prefix.low_bits(prefix_len as u32))
+                        .then_some(node);
+                }
+            }
         }
     }
 
-    fn test_iter_impl(keys: Vec<u64>) {
-        let (tree, reference) = from_keys(keys);
+    fn split_insert(node: &mut Node<V, K>, key: K, value: V) -> &mut V {
+        let other = match node {
+            Node::Leaf(LeafNode { key: k, .. }) => *k,
+            Node::Internal(InternalNode { key_prefix, .. }) => *key_prefix,
+        };
+        let branch_bit = other.branch_bit(key) as u8;
+        let key_prefix = Self::get_prefix(key, branch_bit);
+        let is_left = Self::is_left(key, branch_bit);
 
-        let vec = tree.iter().take(tree.len() + 1).collect::<Vec<_>>();
-        assert_eq!(vec.len(), tree.len());
+        let leaf = Node::Leaf(LeafNode { key, value });
+        replace_with_or_abort(node, |old_node| {
+            let (left, right) = if is_left {
+                (leaf, old_node)
+            } else {
+                (old_node, leaf)
+            };
 
-        let map: BTreeMap<_, String> = vec.into_iter().map(|(k, v)| (k, v.clone())).collect();
-        assert_eq!(map, reference);
-    }
+            Node::Internal(InternalNode {
+                branch_bit,
+                key_prefix,
+                children: Box::new((left, right)),
+            })
+        });
 
-    proptest! {
-        #[test]
-        fn test_insert_with_duplicates(keys in vec(bits::u64::between(0, 10), 0..100)) {
-            test_insertion_impl(keys)
+        match node {
+            Node::Internal(InternalNode { children, .. }) => {
+                let (left, right) = &mut **children;
+                match if is_left { left } else { right } {
+                    Node::Leaf(LeafNode { value, .. }) => value,
+                    Node::Internal { .. } => unreachable!(),
+                }
+            }
+            Node::Leaf { .. } => unreachable!(),
         }
+    }
 
-        #[test]
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        fn aux<V, K: PatriciaKey>(tree: &mut PatriciaTreeMap<V, K>, key: K, value: V) -> Option<V> {
+            let node = tree.find_insertion_point_mut(key);
+            match node {
+                None => {
+                    tree.root = Some(Node::Leaf(LeafNode { key, value }));
+                    None
+                }
+                Some(node) => match node {
+                    Node::Leaf(LeafNode { key: k, value: v }) if k == &key => {
+                        Some(mem::replace(v, value))
+                    }
+                    _ => {
+                        PatriciaTreeMap::split_insert(node, key, value);
+                        None
+                    }
+                },
+            }
+        }
+
+        let res = aux(self, key, value);
+        self.size += res.is_none() as usize;
+        res
+    }
+
+    /// Inserts `value` if `key` is not in the map yet, and returns a reference to it. Otherwise
+    /// the map is left unchanged, and the error holds the existing entry and `value`.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<&mut V, OccupiedError<'_, V, K>> {
+        match self.entry(key) {
+            Entry::Occupied(entry) => Err(OccupiedError { entry, value }),
+            Entry::Vacant(entry) => Ok(entry.insert(value)),
+        }
+    }
+
+    /// Removes the leaf child of the internal node `node`, replacing `node` by the sibling.
+    fn remove_child(node: &mut Node<V, K>, is_left: bool) -> V {
+        let mut res = None;
+        replace_with_or_abort(node, |old_node| match old_node {
+            Node::Internal(InternalNode { children, .. }) => {
+                let (left, right) = *children;
+                let (leaf, sibling) = if is_left {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                if let Node::Leaf(LeafNode { value, .. }) = leaf {
+                    res = Some(value);
+                }
+                sibling
+            }
+            Node::Leaf { .. } => unreachable!(),
+        });
+        res.unwrap()
+    }
+
+    fn remove_root(root: &mut Option<Node<V, K>>) -> V {
+        match root.take() {
+            Some(Node::Leaf(LeafNode { value, .. })) => value,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn entry(&mut self, key: K) -> Entry<'_, V, K> {
+        #[allow(clippy::type_complexity)]
+        fn aux<V, K: PatriciaKey>(
+            node: &mut Node<V, K>,
+            key: K,
+        ) -> Result<(&mut Node<V, K>, bool), &mut Node<V, K>> {
+            let is_left = match node {
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    ..
+                }) if *key_prefix == PatriciaTreeMap::<V, K>::get_prefix(key, *branch_bit) => {
+                    PatriciaTreeMap::<V, K>::is_left(key, *branch_bit)
+                }
+                _ => return Err(node),
+            };
+
+            let is_match = match node {
+                Node::Internal(InternalNode { children, .. }) => matches!(
+                    if is_left { &children.0 } else { &children.1 },
+                    Node::Leaf(LeafNode { key: k, .. }) if *k == key
+                ),
+                Node::Leaf { .. } => unreachable!(),
+            };
+            if is_match {
+                return Ok((node, is_left));
+            }
+
+            let child = match node {
+                Node::Internal(InternalNode { children, .. }) => {
+                    if is_left {
+                        &mut children.0
+                    } else {
+                        &mut children.1
+                    }
+                }
+                Node::Leaf { .. } => unreachable!(),
+            };
+            aux(child, key)
+        }
+
+        count_descent();
+        let PatriciaTreeMap { size, root } = self;
+        let is_match = match root.as_ref() {
+            None => {
+                return Entry::Vacant(VacantEntry {
+                    key,
+                    size,
+                    slot: VacantSlot::Root(root),
+                })
+            }
+            Some(Node::Leaf(LeafNode { key: k, .. })) => *k == key,
+            Some(Node::Internal { .. }) => false,
+        };
+        if is_match {
+            return Entry::Occupied(OccupiedEntry {
+                key,
+                size,
+                slot: LeafSlot::Root(root),
+            });
+        }
+
+        match aux(root.as_mut().unwrap(), key) {
+            Ok((parent, is_left)) => Entry::Occupied(OccupiedEntry {
+                key,
+                size,
+                slot: LeafSlot::Child(parent, is_left),
+            }),
+            Err(node) => Entry::Vacant(VacantEntry {
+                key,
+                size,
+                slot: VacantSlot::Split(node),
+            }),
+        }
+    }
+
+    /// Returns the value of `key`, first inserting the result of `f` if it is absent.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: K, f: F) -> &mut V {
+        self.entry(key).or_insert_with(f)
+    }
+
+    /// Returns the value of `key`, first inserting `default` if it is absent, and whether it was
+    /// inserted.
+    pub fn get_or_insert(&mut self, key: K, default: V) -> (&mut V, bool) {
+        match self.entry(key) {
+            Entry::Occupied(entry) => (entry.into_mut(), false),
+            Entry::Vacant(entry) => (entry.insert(default), true),
+        }
+    }
+
+    pub fn remove(&mut self, key: K) -> Option<V> {
+        fn aux<V, K: PatriciaKey>(node: &mut Node<V, K>, key: K) -> Option<V> {
+            let is_left = match node {
+                Node::Leaf { .. } => return None,
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    ..
+                }) if *key_prefix != PatriciaTreeMap::<V, K>::get_prefix(key, *branch_bit) => {
+                    return None
+                }
+                Node::Internal(InternalNode {
+                    branch_bit,
+                    children,
+                    ..
+                }) => {
+                    let is_left = PatriciaTreeMap::<V, K>::is_left(key, *branch_bit);
+                    let child = if is_left {
+                        &mut children.0
+                    } else {
+                        &mut children.1
+                    };
+                    match child {
+                        Node::Leaf(LeafNode { key: k, .. }) if *k == key => is_left,
+                        Node::Leaf { .. } => return None,
+                        Node::Internal { .. } => return aux(child, key),
+                    }
+                }
+            };
+
+            Some(PatriciaTreeMap::remove_child(node, is_left))
+        }
+
+        let res = match self.root.as_mut() {
+            None => None,
+            Some(Node::Leaf(LeafNode { key: k, .. })) if *k == key => {
+                Some(Self::remove_root(&mut self.root))
+            }
+            Some(node) => aux(node, key),
+        };
+        self.size -= res.is_some() as usize;
+        res
+    }
+
+    /// Removes `key` from the map, returning the stored key and value if it was present.
+    pub fn remove_entry(&mut self, key: K) -> Option<(K, V)> {
+        self.remove(key).map(|value| (key, value))
+    }
+
+    /// Removes all of `keys` from the map, returning how many of them were present.
+    pub fn remove_all(&mut self, keys: impl IntoIterator<Item = K>) -> usize {
+        keys.into_iter()
+            .filter(|&key| self.remove(key).is_some())
+            .count()
+    }
+
+    /// Removes all entries whose keys have the same low `prefix_len` bits as `prefix`, returning
+    /// how many were removed.
+    ///
+    /// These entries make up a single subtree, which is detached as a whole.
+    pub fn remove_prefix(&mut self, prefix: K, prefix_len: u8) -> usize {
+        /// Returns whether all keys below `node` have the low `prefix_len` bits of `prefix`, or
+        /// `None` if only some of them might.
+        fn matches<V, K: PatriciaKey>(
+            node: &Node<V, K>,
+            prefix: K,
+            prefix_len: u32,
+        ) -> Option<bool> {
+            let (key_prefix, bits) = node.prefix();
+            if bits >= prefix_len {
+                Some(key_prefix.low_bits(prefix_len) == prefix.low_bits(prefix_len))
+            } else if key_prefix == prefix.low_bits(bits) {
+                None
+            } else {
+                Some(false)
+            }
+        }
+
+        /// Removes the matching keys below `node`, for which `matches` returned `None`.
+        fn aux<V, K: PatriciaKey>(node: &mut Node<V, K>, prefix: K, prefix_len: u32) -> usize {
+            let (child, is_left) = match node {
+                Node::Internal(InternalNode {
+                    branch_bit,
+                    children,
+                    ..
+                }) => {
+                    if PatriciaTreeMap::<V, K>::is_left(prefix, *branch_bit) {
+                        (&mut children.0, true)
+                    } else {
+                        (&mut children.1, false)
+                    }
+                }
+                Node::Leaf { .. } => unreachable!(),
+            };
+            let removed = match matches(child, prefix, prefix_len) {
+                None => return aux(child, prefix, prefix_len),
+                Some(false) => return 0,
+                Some(true) => child.count_leaves(),
+            };
+
+            // The sibling of the detached subtree takes the place of the node.
+            replace_with_or_abort(node, |old_node| match old_node {
+                Node::Internal(InternalNode { children, .. }) => {
+                    let (left, right) = *children;
+                    if is_left {
+                        right
+                    } else {
+                        left
+                    }
+                }
+                Node::Leaf { .. } => unreachable!(),
+            });
+            removed
+        }
+
+        // A leaf has only `K::BITS` bits to compare, so longer prefixes select the whole key.
+        let prefix_len = (prefix_len as u32).min(K::BITS);
+        let removed = match self.root.as_mut() {
+            None => 0,
+            Some(root) => match matches(root, prefix, prefix_len) {
+                None => aux(root, prefix, prefix_len),
+                Some(false) => 0,
+                Some(true) => {
+                    self.root = None;
+                    self.size
+                }
+            },
+        };
+        self.size -= removed;
+        removed
+    }
+
+    /// Returns the entry with the smallest key, which can be modified or removed in place.
+    pub fn first_entry(&mut self) -> Option<OccupiedEntry<'_, V, K>> {
+        let (key, _) = self.min()?;
+        match self.entry(key) {
+            Entry::Occupied(entry) => Some(entry),
+            Entry::Vacant(_) => unreachable!(),
+        }
+    }
+
+    /// Returns the entry with the largest key, which can be modified or removed in place.
+    pub fn last_entry(&mut self) -> Option<OccupiedEntry<'_, V, K>> {
+        let (key, _) = self.max()?;
+        match self.entry(key) {
+            Entry::Occupied(entry) => Some(entry),
+            Entry::Vacant(_) => unreachable!(),
+        }
+    }
+
+    /// Removes and returns the entry with the smallest key.
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        let (key, _) = self.min()?;
+        self.remove_entry(key)
+    }
+
+    /// Removes and returns the entry with the largest key.
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        let (key, _) = self.max()?;
+        self.remove_entry(key)
+    }
+
+    /// Retains only the entries for which `f` returns `true`, visiting the entries in tree
+    /// order.
+    pub fn retain<F: FnMut(K, &mut V) -> bool>(&mut self, mut f: F) {
+        /// Returns whether any entries below `node` remain. If only one child of an internal node
+        /// keeps entries, it takes the place of the node.
+        fn aux<V, K: PatriciaKey, F: FnMut(K, &mut V) -> bool>(
+            node: &mut Node<V, K>,
+            f: &mut F,
+            removed: &mut usize,
+        ) -> bool {
+            let (keep_left, keep_right) = match node {
+                Node::Leaf(LeafNode { key, value }) => {
+                    let keep = f(*key, value);
+                    *removed += !keep as usize;
+                    return keep;
+                }
+                Node::Internal(InternalNode { children, .. }) => {
+                    let (left, right) = &mut **children;
+                    (aux(left, f, removed), aux(right, f, removed))
+                }
+            };
+
+            if keep_left != keep_right {
+                replace_with_or_abort(node, |old_node| match old_node {
+                    Node::Internal(InternalNode { children, .. }) => {
+                        let (left, right) = *children;
+                        if keep_left {
+                            left
+                        } else {
+                            right
+                        }
+                    }
+                    Node::Leaf { .. } => unreachable!(),
+                });
+            }
+            keep_left || keep_right
+        }
+
+        let mut removed = 0;
+        if let Some(root) = self.root.as_mut() {
+            if !aux(root, &mut f, &mut removed) {
+                self.root = None;
+            }
+        }
+        self.size -= removed;
+    }
+
+    /// Retains only the entries for which `f` returns `true`, like [`retain`](Self::retain).
+    /// The values of the entries that are kept may be modified by `f` in the same pass.
+    pub fn retain_mut<F: FnMut(K, &mut V) -> bool>(&mut self, f: F) {
+        self.retain(f);
+    }
+
+    /// Moves all entries of `other` into `self`. If a key is in both maps, `combine` is called
+    /// with the existing value and the value from `other` instead of replacing the former.
+    pub fn merge_with<F: FnMut(&mut V, V)>(&mut self, other: Self, mut combine: F) {
+        for (key, value) in other {
+            match self.entry(key) {
+                Entry::Occupied(mut entry) => combine(entry.get_mut(), value),
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
+                }
+            }
+        }
+    }
+
+    /// Moves all entries of `other` into `self`, leaving `other` empty. Values from `other`
+    /// replace those of `self` with the same key.
+    pub fn append(&mut self, other: &mut Self) {
+        self.merge_with(mem::take(other), |value, other| *value = other);
+    }
+
+    /// Returns the number of nodes on the longest path from the root to a leaf, or `0` for an
+    /// empty map.
+    pub fn height(&self) -> usize {
+        self.depth_histogram().len()
+    }
+
+    /// Returns the number of leaves at each depth, where the root is at depth `0`.
+    pub fn depth_histogram(&self) -> Vec<usize> {
+        let mut histogram = Vec::new();
+        let mut stack: Vec<(&Node<V, K>, usize)> = self
+            .root
+            .as_ref()
+            .map(|root| (root, 0))
+            .into_iter()
+            .collect();
+        while let Some((node, depth)) = stack.pop() {
+            match node {
+                Node::Leaf { .. } => {
+                    if histogram.len() <= depth {
+                        histogram.resize(depth + 1, 0);
+                    }
+                    histogram[depth] += 1;
+                }
+                Node::Internal(InternalNode { children, .. }) => {
+                    let (left, right) = &**children;
+                    stack.push((right, depth + 1));
+                    stack.push((left, depth + 1));
+                }
+            }
+        }
+        histogram
+    }
+
+    /// Panics if the tree is malformed: the branch bits must strictly increase down every path,
+    /// every key must agree with the `key_prefix` of each node above it and lie on the side of
+    /// its `branch_bit` that matches that bit, and the size must equal the number of leaves.
+    #[cfg(debug_assertions)]
+    pub fn check_invariants(&self) {
+        /// Checks the subtree at `node` and appends its keys to `keys` in tree order.
+        fn aux<V, K: PatriciaKey>(node: &Node<V, K>, min_branch_bit: u32, keys: &mut Vec<K>) {
+            let (key_prefix, branch_bit, left, right) = match node {
+                Node::Leaf(LeafNode { key, .. }) => return keys.push(*key),
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children,
+                }) => (*key_prefix, *branch_bit as u32, &children.0, &children.1),
+            };
+            assert!(
+                min_branch_bit <= branch_bit && branch_bit < K::BITS,
+                "branch bit {} is not between {} and {}",
+                branch_bit,
+                min_branch_bit,
+                K::BITS
+            );
+            assert_eq!(
+                key_prefix.low_bits(branch_bit),
+                key_prefix,
+                "key prefix {:?} has bits at or above branch bit {}",
+                key_prefix,
+                branch_bit
+            );
+
+            let start = keys.len();
+            aux(left, branch_bit + 1, keys);
+            let middle = keys.len();
+            aux(right, branch_bit + 1, keys);
+            for (i, key) in keys.iter().enumerate().skip(start) {
+                assert_eq!(
+                    key.low_bits(branch_bit),
+                    key_prefix,
+                    "key {:?} does not have the prefix {:?}",
+                    key,
+                    key_prefix
+                );
+                assert_eq!(
+                    key.bit(branch_bit),
+                    i >= middle,
+                    "key {:?} is on the wrong side of branch bit {}",
+                    key,
+                    branch_bit
+                );
+            }
+        }
+
+        let mut keys = Vec::new();
+        if let Some(root) = self.root.as_ref() {
+            aux(root, 0, &mut keys);
+        }
+        assert_eq!(
+            keys.len(),
+            self.len(),
+            "size does not match the number of leaves"
+        );
+    }
+
+    /// Returns the number of internal nodes and of leaves in the tree, counted by walking it.
+    /// Every entry is a leaf, and every internal node has two children, so a non-empty map has
+    /// `len()` leaves and `len() - 1` internal nodes.
+    pub fn node_counts(&self) -> (usize, usize) {
+        let (mut internal_nodes, mut leaf_nodes) = (0, 0);
+        let mut stack: Vec<&Node<V, K>> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            match node {
+                Node::Leaf { .. } => leaf_nodes += 1,
+                Node::Internal(InternalNode { children, .. }) => {
+                    let (left, right) = &**children;
+                    internal_nodes += 1;
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        (internal_nodes, leaf_nodes)
+    }
+
+    /// Returns the number of nodes in the tree, internal nodes and leaves alike, which is
+    /// `2 * len() - 1` for a non-empty map, matching
+    /// [`PatriciaArenaMap::allocated_nodes`](crate::arena_map::PatriciaArenaMap::allocated_nodes). Unlike
+    /// [`node_counts`](Self::node_counts), this is derived from the length rather than counted
+    /// by walking the tree.
+    ///
+    /// Leaves are stored inline in the children of their parent, so only the `len() - 1`
+    /// internal nodes take a heap allocation of their own. See
+    /// [`memory_usage`](Self::memory_usage) for the bytes they take up.
+    pub fn allocated_nodes(&self) -> usize {
+        (2 * self.len()).saturating_sub(1)
+    }
+
+    /// Returns the approximate number of heap bytes used by the nodes of the tree. A tree with
+    /// `n` entries has `n - 1` internal nodes, each owning one allocation for its two children,
+    /// while the root is stored in the map itself. Memory owned by the values themselves is not
+    /// included.
+    pub fn memory_usage(&self) -> usize {
+        self.len().saturating_sub(1) * mem::size_of::<(Node<V, K>, Node<V, K>)>()
+    }
+
+    /// Returns [`memory_usage`](Self::memory_usage) plus the sum of `value_size` over all
+    /// values, for values that own heap memory of their own.
+    pub fn memory_usage_with<F: FnMut(&V) -> usize>(&self, value_size: F) -> usize {
+        self.memory_usage() + self.values().map(value_size).sum::<usize>()
+    }
+}
+
+impl<V, K: PatriciaKey> Node<V, K> {
+    /// Returns the bits shared by all keys below this node and how many low bits that is. A
+    /// leaf shares all bits of its key.
+    fn prefix(&self) -> (K, u32) {
+        match self {
+            Node::Leaf(LeafNode { key, .. }) => (*key, K::BITS),
+            Node::Internal(InternalNode {
+                key_prefix,
+                branch_bit,
+                ..
+            }) => (*key_prefix, *branch_bit as u32),
+        }
+    }
+
+    /// Returns the key of the first leaf below this node in tree order, its leftmost one.
+    fn first_key(&self) -> K {
+        let mut node = self;
+        loop {
+            match node {
+                Node::Leaf(LeafNode { key, .. }) => return *key,
+                Node::Internal(InternalNode { children, .. }) => node = &children.0,
+            }
+        }
+    }
+
+    fn count_leaves(&self) -> usize {
+        match self {
+            Node::Leaf { .. } => 1,
+            Node::Internal(InternalNode { children, .. }) => {
+                children.0.count_leaves() + children.1.count_leaves()
+            }
+        }
+    }
+
+    /// Creates an internal node from children that may have become empty. If a child is missing,
+    /// the other one takes the place of the node.
+    fn from_children(
+        key_prefix: K,
+        branch_bit: u8,
+        left: Option<Node<V, K>>,
+        right: Option<Node<V, K>>,
+    ) -> Option<Node<V, K>> {
+        match (left, right) {
+            (Some(left), Some(right)) => Some(Node::Internal(InternalNode {
+                key_prefix,
+                branch_bit,
+                children: Box::new((left, right)),
+            })),
+            (left, right) => left.or(right),
+        }
+    }
+
+    /// Returns a copy of this subtree with `f` applied to every value, in tree order.
+    fn map_values<W, F: FnMut(&V) -> W>(&self, f: &mut F) -> Node<W, K> {
+        match self {
+            Node::Leaf(LeafNode { key, value }) => Node::Leaf(LeafNode {
+                key: *key,
+                value: f(value),
+            }),
+            Node::Internal(InternalNode {
+                key_prefix,
+                branch_bit,
+                children,
+            }) => {
+                let left = children.0.map_values(f);
+                let right = children.1.map_values(f);
+                Node::Internal(InternalNode {
+                    key_prefix: *key_prefix,
+                    branch_bit: *branch_bit,
+                    children: Box::new((left, right)),
+                })
+            }
+        }
+    }
+
+    /// Returns a copy of this subtree with the entries for which `f` returns `Some`, and the
+    /// results as values, calling `f` in tree order.
+    fn filter_map_values<W, F: FnMut(K, &V) -> Option<W>>(&self, f: &mut F) -> Option<Node<W, K>> {
+        match self {
+            Node::Leaf(LeafNode { key, value }) => {
+                f(*key, value).map(|value| Node::Leaf(LeafNode { key: *key, value }))
+            }
+            Node::Internal(InternalNode {
+                key_prefix,
+                branch_bit,
+                children,
+            }) => {
+                let left = children.0.filter_map_values(f);
+                let right = children.1.filter_map_values(f);
+                Node::from_children(*key_prefix, *branch_bit, left, right)
+            }
+        }
+    }
+
+    /// Combines two subtrees with different prefixes under a new internal node.
+    fn join(a: Node<V, K>, b: Node<V, K>) -> Node<V, K> {
+        let (a_prefix, _) = a.prefix();
+        let (b_prefix, _) = b.prefix();
+        let branch_bit = a_prefix.branch_bit(b_prefix) as u8;
+        let key_prefix = PatriciaTreeMap::<V, K>::get_prefix(a_prefix, branch_bit);
+        let (left, right) = if PatriciaTreeMap::<V, K>::is_left(a_prefix, branch_bit) {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        Node::Internal(InternalNode {
+            key_prefix,
+            branch_bit,
+            children: Box::new((left, right)),
+        })
+    }
+}
+
+impl<V, K: PatriciaKey> Node<V, K> {
+    fn is_subset(a: &Node<V, K>, b: &Node<V, K>) -> bool {
+        let (p, m) = a.prefix();
+        let (q, n) = b.prefix();
+        match (a, b) {
+            _ if m == n && p == q => match (a, b) {
+                (
+                    Node::Internal(InternalNode {
+                        children: a_children,
+                        ..
+                    }),
+                    Node::Internal(InternalNode {
+                        children: b_children,
+                        ..
+                    }),
+                ) => {
+                    let ((al, ar), (bl, br)) = (&**a_children, &**b_children);
+                    Node::is_subset(al, bl) && Node::is_subset(ar, br)
+                }
+                _ => true,
+            },
+            (_, Node::Internal(InternalNode { children, .. })) if n < m && p.low_bits(n) == q => {
+                let (left, right) = &**children;
+                if PatriciaTreeMap::<V, K>::is_left(p, n as u8) {
+                    Node::is_subset(a, left)
+                } else {
+                    Node::is_subset(a, right)
+                }
+            }
+            // Either the prefixes differ, or `a` has keys on both sides of a bit that all keys
+            // of `b` agree on.
+            _ => false,
+        }
+    }
+
+    fn is_disjoint(a: &Node<V, K>, b: &Node<V, K>) -> bool {
+        let (p, m) = a.prefix();
+        let (q, n) = b.prefix();
+        match (a, b) {
+            _ if m == n && p == q => match (a, b) {
+                (
+                    Node::Internal(InternalNode {
+                        children: a_children,
+                        ..
+                    }),
+                    Node::Internal(InternalNode {
+                        children: b_children,
+                        ..
+                    }),
+                ) => {
+                    let ((al, ar), (bl, br)) = (&**a_children, &**b_children);
+                    Node::is_disjoint(al, bl) && Node::is_disjoint(ar, br)
+                }
+                _ => false,
+            },
+            (Node::Internal(InternalNode { children, .. }), _) if m < n && q.low_bits(m) == p => {
+                let (left, right) = &**children;
+                if PatriciaTreeMap::<V, K>::is_left(q, m as u8) {
+                    Node::is_disjoint(left, b)
+                } else {
+                    Node::is_disjoint(right, b)
+                }
+            }
+            (_, Node::Internal(InternalNode { children, .. })) if n < m && p.low_bits(n) == q => {
+                let (left, right) = &**children;
+                if PatriciaTreeMap::<V, K>::is_left(p, n as u8) {
+                    Node::is_disjoint(a, left)
+                } else {
+                    Node::is_disjoint(a, right)
+                }
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Structural set operations, following Okasaki and Gill's "Fast Mergeable Integer Maps". Both
+/// trees are descended in lockstep, and whole subtrees are reused when only one side has keys
+/// with their prefix. Values are taken from `a` when a key is present in both trees.
+impl<V: Clone, K: PatriciaKey> Node<V, K> {
+    fn union(a: &Node<V, K>, b: &Node<V, K>) -> Node<V, K> {
+        let (p, m) = a.prefix();
+        let (q, n) = b.prefix();
+        match (a, b) {
+            _ if m == n && p == q => match (a, b) {
+                (
+                    Node::Internal(InternalNode {
+                        children: a_children,
+                        ..
+                    }),
+                    Node::Internal(InternalNode {
+                        children: b_children,
+                        ..
+                    }),
+                ) => {
+                    let ((al, ar), (bl, br)) = (&**a_children, &**b_children);
+                    Node::Internal(InternalNode {
+                        key_prefix: p,
+                        branch_bit: m as u8,
+                        children: Box::new((Node::union(al, bl), Node::union(ar, br))),
+                    })
+                }
+                _ => a.clone(),
+            },
+            (Node::Internal(InternalNode { children, .. }), _) if m < n && q.low_bits(m) == p => {
+                let (left, right) = &**children;
+                let (left, right) = if PatriciaTreeMap::<V, K>::is_left(q, m as u8) {
+                    (Node::union(left, b), right.clone())
+                } else {
+                    (left.clone(), Node::union(right, b))
+                };
+                Node::Internal(InternalNode {
+                    key_prefix: p,
+                    branch_bit: m as u8,
+                    children: Box::new((left, right)),
+                })
+            }
+            (_, Node::Internal(InternalNode { children, .. })) if n < m && p.low_bits(n) == q => {
+                let (left, right) = &**children;
+                let (left, right) = if PatriciaTreeMap::<V, K>::is_left(p, n as u8) {
+                    (Node::union(a, left), right.clone())
+                } else {
+                    (left.clone(), Node::union(a, right))
+                };
+                Node::Internal(InternalNode {
+                    key_prefix: q,
+                    branch_bit: n as u8,
+                    children: Box::new((left, right)),
+                })
+            }
+            _ => Node::join(a.clone(), b.clone()),
+        }
+    }
+
+    fn intersection(a: &Node<V, K>, b: &Node<V, K>) -> Option<Node<V, K>> {
+        let (p, m) = a.prefix();
+        let (q, n) = b.prefix();
+        match (a, b) {
+            _ if m == n && p == q => match (a, b) {
+                (
+                    Node::Internal(InternalNode {
+                        children: a_children,
+                        ..
+                    }),
+                    Node::Internal(InternalNode {
+                        children: b_children,
+                        ..
+                    }),
+                ) => {
+                    let ((al, ar), (bl, br)) = (&**a_children, &**b_children);
+                    Node::from_children(
+                        p,
+                        m as u8,
+                        Node::intersection(al, bl),
+                        Node::intersection(ar, br),
+                    )
+                }
+                _ => Some(a.clone()),
+            },
+            (Node::Internal(InternalNode { children, .. }), _) if m < n && q.low_bits(m) == p => {
+                let (left, right) = &**children;
+                if PatriciaTreeMap::<V, K>::is_left(q, m as u8) {
+                    Node::intersection(left, b)
+                } else {
+                    Node::intersection(right, b)
+                }
+            }
+            (_, Node::Internal(InternalNode { children, .. })) if n < m && p.low_bits(n) == q => {
+                let (left, right) = &**children;
+                if PatriciaTreeMap::<V, K>::is_left(p, n as u8) {
+                    Node::intersection(a, left)
+                } else {
+                    Node::intersection(a, right)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn difference(a: &Node<V, K>, b: &Node<V, K>) -> Option<Node<V, K>> {
+        let (p, m) = a.prefix();
+        let (q, n) = b.prefix();
+        match (a, b) {
+            _ if m == n && p == q => match (a, b) {
+                (
+                    Node::Internal(InternalNode {
+                        children: a_children,
+                        ..
+                    }),
+                    Node::Internal(InternalNode {
+                        children: b_children,
+                        ..
+                    }),
+                ) => {
+                    let ((al, ar), (bl, br)) = (&**a_children, &**b_children);
+                    Node::from_children(
+                        p,
+                        m as u8,
+                        Node::difference(al, bl),
+                        Node::difference(ar, br),
+                    )
+                }
+                _ => None,
+            },
+            (Node::Internal(InternalNode { children, .. }), _) if m < n && q.low_bits(m) == p => {
+                let (left, right) = &**children;
+                let (left, right) = if PatriciaTreeMap::<V, K>::is_left(q, m as u8) {
+                    (Node::difference(left, b), Some(right.clone()))
+                } else {
+                    (Some(left.clone()), Node::difference(right, b))
+                };
+                Node::from_children(p, m as u8, left, right)
+            }
+            (_, Node::Internal(InternalNode { children, .. })) if n < m && p.low_bits(n) == q => {
+                let (left, right) = &**children;
+                if PatriciaTreeMap::<V, K>::is_left(p, n as u8) {
+                    Node::difference(a, left)
+                } else {
+                    Node::difference(a, right)
+                }
+            }
+            _ => Some(a.clone()),
+        }
+    }
+}
+
+impl<V, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    fn from_root(root: Option<Node<V, K>>) -> Self {
+        Self {
+            size: root.as_ref().map_or(0, Node::count_leaves),
+            root,
+        }
+    }
+
+    /// Builds a map from entries in ascending key order. If a key appears more than once, the
+    /// last value is kept.
+    ///
+    /// Rather than descending the tree for every entry, the entries are recursively split on the
+    /// lowest bit in which their keys differ, which takes `O(n * K::BITS)` time.
+    ///
+    /// # Panics
+    ///
+    /// Panics in debug builds if the keys are not in ascending order.
+    pub fn from_sorted_iter(iter: impl IntoIterator<Item = (K, V)>) -> Self {
+        fn build<V, K: PatriciaKey>(mut entries: Vec<(K, V)>) -> Node<V, K> {
+            let first = entries[0].0;
+            let branch_bit = entries
+                .iter()
+                .map(|(key, _)| first.branch_bit(*key))
+                .min()
+                .unwrap();
+            if branch_bit == K::BITS {
+                let (key, value) = entries.pop().unwrap();
+                return Node::Leaf(LeafNode { key, value });
+            }
+
+            let branch_bit = branch_bit as u8;
+            let (left, right) = entries
+                .into_iter()
+                .partition(|(key, _)| PatriciaTreeMap::<V, K>::is_left(*key, branch_bit));
+            Node::Internal(InternalNode {
+                key_prefix: PatriciaTreeMap::<V, K>::get_prefix(first, branch_bit),
+                branch_bit,
+                children: Box::new((build(left), build(right))),
+            })
+        }
+
+        let entries: Vec<(K, V)> = iter.into_iter().collect();
+        debug_assert!(
+            entries.windows(2).all(|pair| pair[0].0 <= pair[1].0),
+            "keys are not in ascending order"
+        );
+        Self::from_root((!entries.is_empty()).then(|| build(entries)))
+    }
+
+    /// Rebuilds a map from the arrays of [`to_flat`](PatriciaTreeMap::to_flat) in one pass,
+    /// without searching for the place of any key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `flat` does not describe a valid tree.
+    pub fn from_flat(flat: FlatPatricia<V, K>) -> Self {
+        struct Builder<'a, V, K> {
+            is_leaf: &'a [bool],
+            branch_bits: &'a [u8],
+            prefixes: &'a [K],
+            right_children: &'a [u32],
+            values: vec::IntoIter<V>,
+        }
+
+        impl<V, K: PatriciaKey> Builder<'_, V, K> {
+            /// Builds the subtree at node `i`, returning it and the index of the node after it.
+            fn build(&mut self, i: usize) -> (Node<V, K>, usize) {
+                assert!(i < self.is_leaf.len(), "node {} is out of bounds", i);
+                if self.is_leaf[i] {
+                    let value = self.values.next().expect("fewer values than leaves");
+                    let leaf = LeafNode {
+                        key: self.prefixes[i],
+                        value,
+                    };
+                    return (Node::Leaf(leaf), i + 1);
+                }
+
+                let key_prefix = self.prefixes[i];
+                let branch_bit = self.branch_bits[i];
+                assert!(
+                    (branch_bit as u32) < K::BITS
+                        && key_prefix
+                            == PatriciaTreeMap::<V, K>::get_prefix(key_prefix, branch_bit),
+                    "node {} has an invalid branch bit or key prefix",
+                    i
+                );
+                let (left, next) = self.build(i + 1);
+                assert_eq!(
+                    self.right_children[i] as usize, next,
+                    "node {} has the wrong right child",
+                    i
+                );
+                let (right, next) = self.build(next);
+                for (child, is_left) in [(&left, true), (&right, false)] {
+                    let (prefix, child_branch_bit) = child.prefix();
+                    assert!(
+                        child_branch_bit > branch_bit as u32
+                            && PatriciaTreeMap::<V, K>::get_prefix(prefix, branch_bit)
+                                == key_prefix
+                            && PatriciaTreeMap::<V, K>::is_left(prefix, branch_bit) == is_left,
+                        "a child of node {} does not belong below it",
+                        i
+                    );
+                }
+                let internal = InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children: Box::new((left, right)),
+                };
+                (Node::Internal(internal), next)
+            }
+        }
+
+        let FlatPatricia {
+            is_leaf,
+            branch_bits,
+            prefixes,
+            right_children,
+            values,
+        } = flat;
+        let n = is_leaf.len();
+        assert!(
+            branch_bits.len() == n && prefixes.len() == n && right_children.len() == n,
+            "the node arrays have different lengths"
+        );
+        let size = values.len();
+        let mut builder = Builder {
+            is_leaf: &is_leaf,
+            branch_bits: &branch_bits,
+            prefixes: &prefixes,
+            right_children: &right_children,
+            values: values.into_iter(),
+        };
+        let root = (n > 0).then(|| {
+            let (root, next) = builder.build(0);
+            assert_eq!(next, n, "not all nodes are below the root");
+            root
+        });
+        assert!(builder.values.next().is_none(), "more values than leaves");
+        Self { size, root }
+    }
+
+    /// Moves the entries with keys `>= key` into a new map and returns it.
+    pub fn split_off(&mut self, key: K) -> Self {
+        /// Splits `node` into the keys below `key` and the rest. Subtrees whose prefix puts all
+        /// of their keys on one side are moved as a whole.
+        #[allow(clippy::type_complexity)]
+        fn split<V, K: PatriciaKey>(
+            node: Node<V, K>,
+            key: K,
+        ) -> (Option<Node<V, K>>, Option<Node<V, K>>) {
+            let (prefix, bits) = node.prefix();
+            if ceil_with_prefix(key, prefix, bits).is_none() {
+                return (Some(node), None);
+            }
+            let below = key.checked_sub_bit(0);
+            if below.is_none_or(|below| floor_with_prefix(below, prefix, bits).is_none()) {
+                return (None, Some(node));
+            }
+
+            match node {
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children,
+                }) => {
+                    let (left, right) = *children;
+                    let (left_below, left_above) = split(left, key);
+                    let (right_below, right_above) = split(right, key);
+                    (
+                        Node::from_children(key_prefix, branch_bit, left_below, right_below),
+                        Node::from_children(key_prefix, branch_bit, left_above, right_above),
+                    )
+                }
+                // A leaf is entirely decided by its prefix.
+                Node::Leaf { .. } => unreachable!(),
+            }
+        }
+
+        let (below, above) = match self.root.take() {
+            Some(root) => split(root, key),
+            None => (None, None),
+        };
+        self.root = below;
+        let other = Self::from_root(above);
+        self.size -= other.len();
+        other
+    }
+
+    /// Returns a map with the same keys, and the result of `f` on each value as values. The tree
+    /// is copied as it is, rather than built by inserting every key.
+    pub fn map_values<W, F: FnMut(&V) -> W>(&self, mut f: F) -> PatriciaTreeMap<W, K> {
+        PatriciaTreeMap {
+            size: self.size,
+            root: self.root.as_ref().map(|root| root.map_values(&mut f)),
+        }
+    }
+
+    /// Returns a map with the entries for which `f` returns `Some`, with the results as values.
+    pub fn filter_map_values<W, F: FnMut(K, &V) -> Option<W>>(
+        &self,
+        mut f: F,
+    ) -> PatriciaTreeMap<W, K> {
+        PatriciaTreeMap::from_root(
+            self.root
+                .as_ref()
+                .and_then(|root| root.filter_map_values(&mut f)),
+        )
+    }
+
+    /// Returns whether every key of `self` is also in `other`.
+    pub(crate) fn is_subset(&self, other: &Self) -> bool {
+        match (self.root.as_ref(), other.root.as_ref()) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(a), Some(b)) => self.len() <= other.len() && Node::is_subset(a, b),
+        }
+    }
+
+    /// Returns whether `self` and `other` have no keys in common.
+    pub(crate) fn is_disjoint(&self, other: &Self) -> bool {
+        match (self.root.as_ref(), other.root.as_ref()) {
+            (Some(a), Some(b)) => Node::is_disjoint(a, b),
+            _ => true,
+        }
+    }
+}
+
+impl<V: Clone, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    /// Flattens the tree into arrays that [`from_flat`](Self::from_flat) rebuilds it from.
+    pub fn to_flat(&self) -> FlatPatricia<V, K> {
+        let mut flat = FlatPatricia {
+            is_leaf: Vec::new(),
+            branch_bits: Vec::new(),
+            prefixes: Vec::new(),
+            right_children: Vec::new(),
+            values: Vec::with_capacity(self.len()),
+        };
+        // Pairs of a node and the internal node whose right child it is, if any.
+        let mut stack: Vec<(&Node<V, K>, Option<usize>)> =
+            self.root.iter().map(|root| (root, None)).collect();
+        while let Some((node, parent)) = stack.pop() {
+            let i = flat.is_leaf.len();
+            if let Some(parent) = parent {
+                flat.right_children[parent] = i as u32;
+            }
+            match node {
+                Node::Leaf(LeafNode { key, value }) => {
+                    flat.is_leaf.push(true);
+                    flat.branch_bits.push(0);
+                    flat.prefixes.push(*key);
+                    flat.values.push(value.clone());
+                }
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children,
+                }) => {
+                    flat.is_leaf.push(false);
+                    flat.branch_bits.push(*branch_bit);
+                    flat.prefixes.push(*key_prefix);
+                    stack.push((&children.1, Some(i)));
+                    stack.push((&children.0, None));
+                }
+            }
+            flat.right_children.push(0);
+        }
+        flat
+    }
+
+    /// Returns a map with the entries of both maps, preferring the values of `self`.
+    pub(crate) fn union(&self, other: &Self) -> Self {
+        Self::from_root(match (self.root.as_ref(), other.root.as_ref()) {
+            (Some(a), Some(b)) => Some(Node::union(a, b)),
+            (a, b) => a.or(b).cloned(),
+        })
+    }
+
+    /// Returns a map with the entries of `self` whose keys are also in `other`.
+    pub(crate) fn intersection(&self, other: &Self) -> Self {
+        Self::from_root(match (self.root.as_ref(), other.root.as_ref()) {
+            (Some(a), Some(b)) => Node::intersection(a, b),
+            _ => None,
+        })
+    }
+
+    /// Returns a map with the entries of `self` whose keys are not in `other`.
+    pub(crate) fn difference(&self, other: &Self) -> Self {
+        Self::from_root(match (self.root.as_ref(), other.root.as_ref()) {
+            (Some(a), Some(b)) => Node::difference(a, b),
+            (a, _) => a.cloned(),
+        })
+    }
+
+    /// Returns a map with the entries whose keys are in exactly one of the maps.
+    pub(crate) fn symmetric_difference(&self, other: &Self) -> Self {
+        self.difference(other).union(&other.difference(self))
+    }
+}
+
+impl<V: Display, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    /// Returns the tree as a Graphviz DOT graph. Internal nodes are labelled with their
+    /// `key_prefix` and `branch_bit`, and leaves with their key and value.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph {\n");
+        let mut stack: Vec<(&Node<V, K>, usize)> = self
+            .root
+            .as_ref()
+            .map(|root| (root, 0))
+            .into_iter()
+            .collect();
+        let mut next_id = stack.len();
+        while let Some((node, id)) = stack.pop() {
+            match node {
+                Node::Leaf(LeafNode { key, value }) => {
+                    let value = value.to_string().replace('\\', "\\\\").replace('"', "\\\"");
+                    writeln!(
+                        dot,
+                        "    n{} [shape=box, label=\"key: {:?}\\nvalue: {}\"];",
+                        id, key, value
+                    )
+                    .unwrap();
+                }
+                Node::Internal(InternalNode {
+                    key_prefix,
+                    branch_bit,
+                    children,
+                }) => {
+                    let (left, right) = &**children;
+                    writeln!(
+                        dot,
+                        "    n{} [label=\"key_prefix: {:?}\\nbranch_bit: {}\"];",
+                        id, key_prefix, branch_bit
+                    )
+                    .unwrap();
+                    for (bit, child) in [(1, right), (0, left)] {
+                        writeln!(dot, "    n{} -> n{} [label={}];", id, next_id, bit).unwrap();
+                        stack.push((child, next_id));
+                        next_id += 1;
+                    }
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl<V: Debug, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    /// Returns the nodes of the tree for debug formatting, rather than the entries printed by the
+    /// map's own [`Debug`] implementation.
+    pub fn debug_tree(&self) -> impl Debug + '_ {
+        &self.root
+    }
+}
+
+/// Formats the map like a map literal, with its entries in ascending key order.
+impl<V: Debug, K: PatriciaKey> Debug for PatriciaTreeMap<V, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<V: Clone, K: Clone> Clone for PatriciaTreeMap<V, K> {
+    fn clone(&self) -> Self {
+        Self {
+            size: self.size,
+            root: self.root.clone(),
+        }
+    }
+
+    /// Reuses the nodes of `self` wherever the tree has the same shape as that of `source`, which
+    /// is cheap when `self` is an earlier clone of a slowly changing map.
+    fn clone_from(&mut self, source: &Self) {
+        self.size = source.size;
+        self.root.clone_from(&source.root);
+    }
+}
+
+impl<V, K: PatriciaKey> Default for PatriciaTreeMap<V, K> {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            root: None,
+        }
+    }
+}
+
+/// Tears the tree down with an explicit stack rather than through the recursive drops of the
+/// nested boxes.
+impl<V, K> Drop for PatriciaTreeMap<V, K> {
+    fn drop(&mut self) {
+        Node::drop_all(self.root.take().into_iter().collect());
+    }
+}
+
+impl<V, K> Node<V, K> {
+    /// Drops the trees at `stack` one node at a time.
+    fn drop_all(mut stack: Vec<Self>) {
+        while let Some(node) = stack.pop() {
+            if let Node::Internal(InternalNode { children, .. }) = node {
+                let (left, right) = *children;
+                stack.push(right);
+                stack.push(left);
+            }
+        }
+    }
+}
+
+pub enum Entry<'a, V, K = u64> {
+    Occupied(OccupiedEntry<'a, V, K>),
+    Vacant(VacantEntry<'a, V, K>),
+}
+
+pub struct OccupiedEntry<'a, V, K = u64> {
+    key: K,
+    size: &'a mut usize,
+    slot: LeafSlot<'a, V, K>,
+}
+
+pub struct VacantEntry<'a, V, K = u64> {
+    key: K,
+    size: &'a mut usize,
+    slot: VacantSlot<'a, V, K>,
+}
+
+/// The error returned by [`PatriciaTreeMap::try_insert`] when the key is already in the map.
+pub struct OccupiedError<'a, V, K = u64> {
+    /// The entry of the key, which was left unchanged.
+    pub entry: OccupiedEntry<'a, V, K>,
+    /// The value that was not inserted.
+    pub value: V,
+}
+
+impl<'a, V: Debug, K: PatriciaKey> Debug for OccupiedError<'a, V, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OccupiedError")
+            .field("key", &self.entry.key())
+            .field("old_value", self.entry.get())
+            .field("new_value", &self.value)
+            .finish()
+    }
+}
+
+impl<'a, V: Debug, K: PatriciaKey> Display for OccupiedError<'a, V, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to insert {:?}, key {:?} already exists with value {:?}",
+            self.value,
+            self.entry.key(),
+            self.entry.get()
+        )
+    }
+}
+
+impl<'a, V: Debug, K: PatriciaKey> core::error::Error for OccupiedError<'a, V, K> {}
+
+/// Location of an existing leaf: either the root itself or a child of an internal node.
+enum LeafSlot<'a, V, K> {
+    Root(&'a mut Option<Node<V, K>>),
+    Child(&'a mut Node<V, K>, bool),
+}
+
+/// Location where a missing key is inserted: an empty root or the node to split.
+enum VacantSlot<'a, V, K> {
+    Root(&'a mut Option<Node<V, K>>),
+    Split(&'a mut Node<V, K>),
+}
+
+impl<'a, V, K: PatriciaKey> LeafSlot<'a, V, K> {
+    #[duplicate_item(
+      method          self_type    reference(type) borrow(v) as_ref(v);
+      [leaf]          [&Self]      [& type]        [&v]      [v.as_ref()];
+      [leaf_mut]      [&mut Self]  [&mut type]     [&mut v]  [v.as_mut()];
+      [into_leaf_mut] [Self]       [&'a mut type]  [&mut v]  [v.as_mut()];
+    )]
+    #[allow(clippy::needless_arbitrary_self_type)]
+    fn method(self: self_type) -> reference([LeafNode<V, K>]) {
+        let node = match self {
+            LeafSlot::Root(root) => as_ref([root]),
+            LeafSlot::Child(Node::Internal(InternalNode { children, .. }), true) => {
+                Some(borrow([children.0]))
+            }
+            LeafSlot::Child(Node::Internal(InternalNode { children, .. }), false) => {
+                Some(borrow([children.1]))
+            }
+            LeafSlot::Child(Node::Leaf { .. }, _) => None,
+        };
+        match node {
+            Some(Node::Leaf(leaf)) => leaf,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<'a, V, K: PatriciaKey> Entry<'a, V, K> {
+    pub fn key(&self) -> K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        self.or_insert_with_key(|_| default())
+    }
+
+    pub fn or_insert_with_key<F: FnOnce(K) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let value = default(entry.key());
+                entry.insert(value)
+            }
+        }
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Entry::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, V: Default, K: PatriciaKey> Entry<'a, V, K> {
+    pub fn or_default(self) -> &'a mut V {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<'a, V, K: PatriciaKey> OccupiedEntry<'a, V, K> {
+    pub fn key(&self) -> K {
+        self.key
+    }
+
+    pub fn get(&self) -> &V {
+        &self.slot.leaf().value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.slot.leaf_mut().value
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.slot.into_leaf_mut().value
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        *self.size -= 1;
+        match self.slot {
+            LeafSlot::Root(root) => PatriciaTreeMap::remove_root(root),
+            LeafSlot::Child(node, is_left) => PatriciaTreeMap::remove_child(node, is_left),
+        }
+    }
+}
+
+impl<'a, V, K: PatriciaKey> VacantEntry<'a, V, K> {
+    pub fn key(&self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        *self.size += 1;
+        match self.slot {
+            VacantSlot::Root(root) => {
+                match root.insert(Node::Leaf(LeafNode {
+                    key: self.key,
+                    value,
+                })) {
+                    Node::Leaf(LeafNode { value, .. }) => value,
+                    Node::Internal { .. } => unreachable!(),
+                }
+            }
+            VacantSlot::Split(node) => PatriciaTreeMap::split_insert(node, self.key, value),
+        }
+    }
+}
+
+/// The nodes of a [`PatriciaTreeMap`] as seen by a [`Cursor`], through handles of type `N`.
+struct Nodes<N>(PhantomData<N>);
+
+#[duplicate_item(
+  reference(type) entry            borrow(v);
+  [&'a type]      [(K, &'a V)]     [&v];
+  [&'a mut type]  [(K, &'a mut V)] [&mut v];
+)]
+impl<'a, V, K: PatriciaKey> Tree<K> for Nodes<reference([Node<V, K>])> {
+    type Node = reference([Node<V, K>]);
+    type Entry = entry;
+
+    fn prefix(&self, node: &Self::Node) -> (K, u32) {
+        node.prefix()
+    }
+
+    fn open(&mut self, node: Self::Node) -> Result<Self::Entry, [Self::Node; 2]> {
+        match node {
+            Node::Leaf(LeafNode { key, value }) => Ok((*key, value)),
+            Node::Internal(InternalNode { children, .. }) => {
+                let (left, right) = borrow([**children]);
+                Err([left, right])
+            }
+        }
+    }
+}
+
+impl<V, K: PatriciaKey> Tree<K> for Nodes<Node<V, K>> {
+    type Node = Node<V, K>;
+    type Entry = (K, V);
+
+    fn prefix(&self, node: &Self::Node) -> (K, u32) {
+        node.prefix()
+    }
+
+    fn open(&mut self, node: Self::Node) -> Result<Self::Entry, [Self::Node; 2]> {
+        match node {
+            Node::Leaf(LeafNode { key, value }) => Ok((key, value)),
+            Node::Internal(InternalNode { children, .. }) => {
+                let (left, right) = *children;
+                Err([left, right])
+            }
+        }
+    }
+}
+
+duplicate! {
+  [
+    name         reference(type);
+    [Iter]       [&'a type];
+    [IterMut]    [&'a mut type];
+    [Range]      [&'a type];
+    [RangeMut]   [&'a mut type];
+    [PrefixIter] [&'a type];
+  ]
+    pub struct name<'a, V, K = u64> {
+        cursor: Cursor<Nodes<reference([Node<V, K>])>, reference([Node<V, K>]), K>,
+    }
+
+    impl<'a, V, K: PatriciaKey> name<'a, V, K> {
+        /// Creates an iterator over the entries below `root` with keys in the inclusive range
+        /// `start..=end`, of which there are between `len.0` and `len.1`.
+        fn new(root: Option<reference([Node<V, K>])>, range: (K, K), len: (usize, usize)) -> Self {
+            Self {
+                cursor: Cursor::new(Nodes(PhantomData), root, range, len),
+            }
+        }
+    }
+
+    impl<'a, V, K: PatriciaKey> Iterator for name<'a, V, K> {
+        type Item = (K, reference([V]));
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.cursor.next()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.cursor.size_hint()
+        }
+    }
+
+    impl<'a, V, K: PatriciaKey> DoubleEndedIterator for name<'a, V, K> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.cursor.next_back()
+        }
+    }
+
+    impl<'a, V, K: PatriciaKey> FusedIterator for name<'a, V, K> {}
+}
+
+/// Unlike the other iterators over entries, these visit the whole map, so they know how many
+/// entries are left.
+#[duplicate_item(name; [Iter]; [IterMut];)]
+impl<'a, V, K: PatriciaKey> ExactSizeIterator for name<'a, V, K> {}
+
+impl<V, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    /// Returns an iterator over the entries of the map in ascending key order.
+    pub fn iter(&self) -> Iter<'_, V, K> {
+        Iter::new(
+            self.root.as_ref(),
+            (K::MIN, K::MAX),
+            (self.len(), self.len()),
+        )
+    }
+
+    /// Returns an iterator over the entries with keys `>= start` in ascending key order, for
+    /// resuming iteration after the last key seen. This is shorthand for
+    /// [`range(start..)`](Self::range).
+    pub fn iter_from(&self, start: K) -> Range<'_, V, K> {
+        self.range(start..)
+    }
+
+    /// Returns an iterator over the entries of the map in ascending key order, with mutable
+    /// references to the values.
+    pub fn iter_mut(&mut self) -> IterMut<'_, V, K> {
+        let len = self.len();
+        IterMut::new(self.root.as_mut(), (K::MIN, K::MAX), (len, len))
+    }
+
+    /// Returns an iterator over the entries with keys in `range`, in ascending key order.
+    pub fn range(&self, range: impl RangeBounds<K>) -> Range<'_, V, K> {
+        let range = inclusive_bounds(range);
+        Range::new(self.root.as_ref(), range, (0, self.len()))
+    }
+
+    /// Returns an iterator over the entries with keys in `range`, in ascending key order, with
+    /// mutable references to the values.
+    pub fn range_mut(&mut self, range: impl RangeBounds<K>) -> RangeMut<'_, V, K> {
+        let (range, len) = (inclusive_bounds(range), self.len());
+        RangeMut::new(self.root.as_mut(), range, (0, len))
+    }
+
+    /// Returns an iterator over the entries whose keys have the same low `prefix_len` bits as
+    /// `prefix`, in ascending key order.
+    pub fn prefix_iter(&self, prefix: K, prefix_len: u8) -> PrefixIter<'_, V, K> {
+        PrefixIter::new(
+            self.find_prefix_subtree(prefix, prefix_len),
+            (K::MIN, K::MAX),
+            (0, self.len()),
+        )
+    }
+}
+
+duplicate! {
+  [
+    name        inner_iter  item         project;
+    [Keys]      [Iter]      [K]          [|(key, _)| key];
+    [Values]    [Iter]      [&'a V]      [|(_, value)| value];
+    [ValuesMut] [IterMut]   [&'a mut V]  [|(_, value)| value];
+  ]
+    pub struct name<'a, V, K = u64> {
+        inner: inner_iter<'a, V, K>,
+    }
+
+    impl<'a, V, K: PatriciaKey> Iterator for name<'a, V, K> {
+        type Item = item;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(project)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<'a, V, K: PatriciaKey> DoubleEndedIterator for name<'a, V, K> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back().map(project)
+        }
+    }
+
+    impl<'a, V, K: PatriciaKey> ExactSizeIterator for name<'a, V, K> {}
+
+    impl<'a, V, K: PatriciaKey> FusedIterator for name<'a, V, K> {}
+}
+
+impl<V, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    /// Returns an iterator over the keys of the map in ascending order.
+    pub fn keys(&self) -> Keys<'_, V, K> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Returns an iterator over the values of the map in ascending key order.
+    pub fn values(&self) -> Values<'_, V, K> {
+        Values { inner: self.iter() }
+    }
+
+    /// Returns an iterator over mutable references to the values of the map in ascending key
+    /// order.
+    pub fn values_mut(&mut self) -> ValuesMut<'_, V, K> {
+        ValuesMut {
+            inner: self.iter_mut(),
+        }
+    }
+
+    /// Folds the values of the map into an accumulator with `f`, in ascending key order.
+    pub fn fold_values<B, F: FnMut(B, &V) -> B>(&self, init: B, f: F) -> B {
+        self.values().fold(init, f)
+    }
+}
+
+impl<V: PartialEq, K: PatriciaKey> PartialEq for PatriciaTreeMap<V, K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<V: Eq, K: PatriciaKey> Eq for PatriciaTreeMap<V, K> {}
+
+impl<V: PartialOrd, K: PatriciaKey> PartialOrd for PatriciaTreeMap<V, K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+/// Maps are compared lexicographically as sequences of `(key, value)` pairs in ascending key
+/// order.
+///
+/// On an owned map, `map.min()` and `map.max()` resolve to [`Ord::min`] and [`Ord::max`]; use
+/// [`first_key_value`](PatriciaTreeMap::first_key_value) and
+/// [`last_key_value`](PatriciaTreeMap::last_key_value) or call the methods on a reference.
+impl<V: Ord, K: PatriciaKey> Ord for PatriciaTreeMap<V, K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+impl<V: Hash, K: PatriciaKey> Hash for PatriciaTreeMap<V, K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        for entry in self.iter() {
+            entry.hash(state);
+        }
+    }
+}
+
+impl<V, K: PatriciaKey> Index<K> for PatriciaTreeMap<V, K> {
+    type Output = V;
+
+    /// Returns a reference to the value of `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is not in the map.
+    fn index(&self, key: K) -> &V {
+        match self.get(key) {
+            Some(value) => value,
+            None => panic!("key {:?} not found in PatriciaTreeMap", key),
+        }
+    }
+}
+
+/// Builds a map from `(key, value)` pairs. Like [`PatriciaTreeMap::insert`], later values
+/// replace earlier ones with the same key.
+impl<V, K: PatriciaKey> FromIterator<(K, V)> for PatriciaTreeMap<V, K> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::default();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+/// Builds a map with `u64` keys from `(key, value)` pairs, where later values replace earlier
+/// ones with the same key. Maps with other key types are built with [`FromIterator`].
+impl<V, const N: usize> From<[(u64, V); N]> for PatriciaTreeMap<V> {
+    fn from(entries: [(u64, V); N]) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+/// Builds a map with `u64` keys from `(key, value)` pairs, where later values replace earlier
+/// ones with the same key. Maps with other key types are built with [`FromIterator`].
+impl<V> From<Vec<(u64, V)>> for PatriciaTreeMap<V> {
+    fn from(entries: Vec<(u64, V)>) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+impl<V, K: PatriciaKey> From<BTreeMap<K, V>> for PatriciaTreeMap<V, K> {
+    fn from(map: BTreeMap<K, V>) -> Self {
+        Self::from_sorted_iter(map)
+    }
+}
+
+impl<V, K: PatriciaKey> From<PatriciaTreeMap<V, K>> for BTreeMap<K, V> {
+    fn from(map: PatriciaTreeMap<V, K>) -> Self {
+        map.into_iter().collect()
+    }
+}
+
+impl<V, K: PatriciaKey> Extend<(K, V)> for PatriciaTreeMap<V, K> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<'a, V: Clone, K: PatriciaKey> Extend<(&'a K, &'a V)> for PatriciaTreeMap<V, K> {
+    fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(key, value)| (*key, value.clone())));
+    }
+}
+
+pub struct IntoIter<V, K = u64> {
+    cursor: Cursor<Nodes<Node<V, K>>, Node<V, K>, K>,
+}
+
+impl<V, K: PatriciaKey> IntoIter<V, K> {
+    fn new(mut map: PatriciaTreeMap<V, K>) -> Self {
+        let len = map.len();
+        Self {
+            cursor: Cursor::new(
+                Nodes(PhantomData),
+                map.root.take(),
+                (K::MIN, K::MAX),
+                (len, len),
+            ),
+        }
+    }
+}
+
+impl<V, K: PatriciaKey> Iterator for IntoIter<V, K> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.cursor.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.cursor.size_hint()
+    }
+}
+
+impl<V, K: PatriciaKey> DoubleEndedIterator for IntoIter<V, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.cursor.next_back()
+    }
+}
+
+/// Drops the subtrees that were not consumed like [`PatriciaTreeMap`] drops its tree.
+impl<V, K> Drop for IntoIter<V, K> {
+    fn drop(&mut self) {
+        Node::drop_all(self.cursor.take_nodes().collect());
+    }
+}
+
+impl<V, K: PatriciaKey> ExactSizeIterator for IntoIter<V, K> {}
+
+impl<V, K: PatriciaKey> FusedIterator for IntoIter<V, K> {}
+
+impl<V, K: PatriciaKey> IntoIterator for PatriciaTreeMap<V, K> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<V, K>;
+
+    /// Consumes the map, returning its entries in ascending key order.
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter::new(self)
+    }
+}
+
+duplicate! {
+  [
+    name         item  project;
+    [IntoKeys]   [K]   [|(key, _)| key];
+    [IntoValues] [V]   [|(_, value)| value];
+  ]
+    pub struct name<V, K = u64> {
+        inner: IntoIter<V, K>,
+    }
+
+    impl<V, K: PatriciaKey> Iterator for name<V, K> {
+        type Item = item;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.inner.next().map(project)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.inner.size_hint()
+        }
+    }
+
+    impl<V, K: PatriciaKey> DoubleEndedIterator for name<V, K> {
+        fn next_back(&mut self) -> Option<Self::Item> {
+            self.inner.next_back().map(project)
+        }
+    }
+
+    impl<V, K: PatriciaKey> ExactSizeIterator for name<V, K> {}
+
+    impl<V, K: PatriciaKey> FusedIterator for name<V, K> {}
+}
+
+impl<V, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    /// Consumes the map, returning its keys in ascending order.
+    pub fn into_keys(self) -> IntoKeys<V, K> {
+        IntoKeys {
+            inner: self.into_iter(),
+        }
+    }
+
+    /// Consumes the map, returning its values in ascending key order.
+    pub fn into_values(self) -> IntoValues<V, K> {
+        IntoValues {
+            inner: self.into_iter(),
+        }
+    }
+}
+
+pub struct Drain<'a, V, K = u64> {
+    inner: IntoIter<V, K>,
+    marker: PhantomData<&'a mut PatriciaTreeMap<V, K>>,
+}
+
+impl<'a, V, K: PatriciaKey> Iterator for Drain<'a, V, K> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, V, K: PatriciaKey> DoubleEndedIterator for Drain<'a, V, K> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+impl<'a, V, K: PatriciaKey> ExactSizeIterator for Drain<'a, V, K> {}
+
+impl<'a, V, K: PatriciaKey> FusedIterator for Drain<'a, V, K> {}
+
+impl<V, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    /// Removes all entries from the map, returning them in ascending key order. The map is empty
+    /// as soon as this returns, even if the iterator is not consumed.
+    pub fn drain(&mut self) -> Drain<'_, V, K> {
+        Drain {
+            inner: mem::take(self).into_iter(),
+            marker: PhantomData,
+        }
+    }
+}
+
+/// An iterator removing the entries of a map that match a predicate, created by
+/// [`PatriciaTreeMap::extract_if`].
+pub struct ExtractIf<'a, V, F, K = u64> {
+    map: &'a mut PatriciaTreeMap<V, K>,
+    /// The key of the next entry to visit. The iterator borrows the map mutably, so nothing else
+    /// can remove it in the meantime.
+    next: Option<K>,
+    pred: F,
+}
+
+impl<'a, V, F: FnMut(K, &mut V) -> bool, K: PatriciaKey> Iterator for ExtractIf<'a, V, F, K> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.next {
+            let (removed, next) = self.map.extract_step(key, &mut self.pred);
+            self.next = next;
+            if let Some(value) = removed {
+                return Some((key, value));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.next {
+            Some(_) => (0, Some(self.map.len())),
+            None => (0, Some(0)),
+        }
+    }
+}
+
+impl<'a, V, F: FnMut(K, &mut V) -> bool, K: PatriciaKey> FusedIterator for ExtractIf<'a, V, F, K> {}
+
+impl<V, K: PatriciaKey> PatriciaTreeMap<V, K> {
+    /// Returns an iterator that visits the entries in tree order, like [`retain`](Self::retain),
+    /// and removes and yields those for which `pred` returns `true`. Entries not yet visited
+    /// when the iterator is dropped stay in the map.
+    ///
+    /// Each entry takes a single descent from the root, which also finds the next entry: tree
+    /// order does not depend on the shape of the tree, so removing entries does not change it.
+    pub fn extract_if<F: FnMut(K, &mut V) -> bool>(&mut self, pred: F) -> ExtractIf<'_, V, F, K> {
+        ExtractIf {
+            next: self.root.as_ref().map(Node::first_key),
+            map: self,
+            pred,
+        }
+    }
+
+    /// Calls `pred` on the entry of `key`, which must be in the map, and removes the entry if
+    /// that returns `true`. Returns the removed value, and the key of the entry after `key` in
+    /// tree order, which is the first one below the right sibling of the last left turn on the
+    /// way to `key`.
+    fn extract_step<F: FnMut(K, &mut V) -> bool>(
+        &mut self,
+        key: K,
+        pred: &mut F,
+    ) -> (Option<V>, Option<K>) {
+        count_descent();
+        let mut node = match self.root.as_mut() {
+            None => return (None, None),
+            Some(Node::Leaf(LeafNode { key: k, value })) => {
+                debug_assert!(*k == key);
+                if !pred(*k, value) {
+                    return (None, None);
+                }
+                self.size -= 1;
+                return (Some(Self::remove_root(&mut self.root)), None);
+            }
+            Some(node) => node,
+        };
+        // Descend to the parent of the leaf, splitting the borrow of each node between the
+        // child on the way and its sibling.
+        let mut successor = None;
+        let is_left = loop {
+            let (is_left, reached) = match &*node {
+                Node::Internal(InternalNode {
+                    branch_bit,
+                    children,
+                    ..
+                }) => {
+                    let is_left = Self::is_left(key, *branch_bit);
+                    let child = if is_left { &children.0 } else { &children.1 };
+                    (is_left, matches!(child, Node::Leaf { .. }))
+                }
+                Node::Leaf { .. } => unreachable!(),
+            };
+            if reached {
+                break is_left;
+            }
+            let Node::Internal(InternalNode { children, .. }) = node else {
+                unreachable!()
+            };
+            let (left, right) = &mut **children;
+            node = if is_left {
+                successor = Some(&*right);
+                left
+            } else {
+                right
+            };
+        };
+        let Node::Internal(InternalNode { children, .. }) = node else {
+            unreachable!()
+        };
+        let next = if is_left {
+            Some(children.1.first_key())
+        } else {
+            successor.map(Node::first_key)
+        };
+        let child = if is_left {
+            &mut children.0
+        } else {
+            &mut children.1
+        };
+        let Node::Leaf(LeafNode { key: k, value }) = child else {
+            unreachable!()
+        };
+        debug_assert!(*k == key);
+        if !pred(*k, value) {
+            return (None, next);
+        }
+        self.size -= 1;
+        (Some(Self::remove_child(node, is_left)), next)
+    }
+}
+
+impl<'a, V, K: PatriciaKey> IntoIterator for &'a PatriciaTreeMap<V, K> {
+    type Item = (K, &'a V);
+    type IntoIter = Iter<'a, V, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, V, K: PatriciaKey> IntoIterator for &'a mut PatriciaTreeMap<V, K> {
+    type Item = (K, &'a mut V);
+    type IntoIter = IterMut<'a, V, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::Entry;
+    use super::FlatPatricia;
+    use super::PatriciaTreeMap;
+    use crate::key::PatriciaKey;
+    use proptest::bits;
+    use proptest::collection::hash_set;
+    use proptest::collection::vec;
+    use proptest::collection::SizeRange;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::BTreeMap;
+    use std::collections::HashMap;
+    use std::hash::{Hash, Hasher};
+    use std::ops::{Bound, RangeBounds};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_empty_map() {
+        let map = PatriciaTreeMap::<String>::new();
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.get(0), None);
+        assert_eq!(map.iter().next(), None);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut map = PatriciaTreeMap::<&'static str>::new();
+        assert_eq!(map.iter().next(), None);
+
+        map.insert(0b001, "B");
+        let mut iter = map.iter();
+        assert_eq!(iter.next(), Some((0b001, &"B")));
+        assert_eq!(iter.next(), None);
+
+        map.insert(0b011, "C");
+        map.insert(0b010, "A");
+        let mut iter = map.iter();
+        assert_eq!(iter.next(), Some((0b001, &"B")));
+        assert_eq!(iter.next(), Some((0b010, &"A")));
+        assert_eq!(iter.next(), Some((0b011, &"C")));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_iter_ascending() {
+        let mut map = PatriciaTreeMap::<()>::new();
+        map.insert(3, ());
+        map.insert(1, ());
+        map.insert(2, ());
+        assert_eq!(
+            map.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_iter_mut() {
+        let mut map = PatriciaTreeMap::<u64>::new();
+        for key in 0..100 {
+            map.insert(key * 37, key);
+        }
+        for (_, value) in map.iter_mut() {
+            *value *= 2;
+        }
+        for key in 0..100 {
+            assert_eq!(map.get(key * 37), Some(&(key * 2)));
+        }
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let keys = [5, 1000, 3, 1 << 40, 0, 17];
+        let mut map = PatriciaTreeMap::<String>::new();
+        for &key in keys.iter() {
+            map.insert(key, key.to_string());
+        }
+
+        let mut entries: Vec<_> = map.into_iter().collect();
+        entries.sort();
+        let mut expected: Vec<_> = keys.iter().map(|&key| (key, key.to_string())).collect();
+        expected.sort();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn test_keys_values() {
+        let keys = [5, 1000, 3, 1 << 40, 0, 17, 3, 5];
+        let mut map = PatriciaTreeMap::<u64>::new();
+        for &key in keys.iter() {
+            map.insert(key, key + 1);
+        }
+
+        let mut expected: Vec<_> = keys.to_vec();
+        expected.sort_unstable();
+        expected.dedup();
+        assert_eq!(map.keys().collect::<Vec<_>>(), expected);
+        assert_eq!(
+            map.values().copied().collect::<Vec<_>>(),
+            expected.iter().map(|key| key + 1).collect::<Vec<_>>()
+        );
+
+        for value in map.values_mut() {
+            *value -= 1;
+        }
+        assert_eq!(map.values().copied().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_into_keys_values() {
+        let map: PatriciaTreeMap<String> = [(1 << 40, "c"), (2, "b"), (1, "a")]
+            .map(|(k, v)| (k, v.to_string()))
+            .into();
+        assert_eq!(map.clone().into_keys().collect::<Vec<_>>(), [1, 2, 1 << 40]);
+        assert_eq!(map.clone().into_keys().next_back(), Some(1 << 40));
+        let values: Vec<String> = map.into_values().collect();
+        assert_eq!(values, ["a", "b", "c"]);
+        assert_eq!(PatriciaTreeMap::<String>::new().into_values().len(), 0);
+    }
+
+    #[test]
+    fn test_fold_values() {
+        let map: PatriciaTreeMap<u64> = (0..100).map(|key| (key * 7919, key * key)).collect();
+        let mut sum = 0;
+        for key in 0..100 {
+            sum += key * key;
+        }
+        assert_eq!(map.fold_values(0, |sum, value| sum + value), sum);
+
+        let map = PatriciaTreeMap::from([(1 << 40, "c"), (2, "b"), (1, "a")]);
+        let joined = map.fold_values(String::new(), |joined, value| joined + value);
+        assert_eq!(joined, "abc");
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let map: PatriciaTreeMap<&str> = vec![(1, "a"), (2, "b"), (1, "c")].into_iter().collect();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(1), Some(&"c"));
+        assert_eq!(map.get(2), Some(&"b"));
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut map: PatriciaTreeMap<&str> = vec![(1, "a"), (2, "b")].into_iter().collect();
+        map.extend(vec![(2, "c"), (3, "d")]);
+        map.extend([(&4, &"e"), (&1, &"f")]);
+        assert_eq!(map.len(), 4);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            vec![(1, &"f"), (2, &"c"), (3, &"d"), (4, &"e")]
+        );
+    }
+
+    #[test]
+    fn test_for_loop() {
+        let mut map: PatriciaTreeMap<u64> = (0..10).map(|key| (key, key)).collect();
+        for (key, value) in &mut map {
+            *value += key;
+        }
+        let mut sum = 0;
+        for (key, value) in &map {
+            assert_eq!(*value, 2 * key);
+            sum += value;
+        }
+        assert_eq!(sum, 90);
+    }
+
+    #[test]
+    fn test_iter_rev() {
+        let map: PatriciaTreeMap<u64> = [3, 1 << 63, 7, 0, 12].iter().map(|&k| (k, k)).collect();
+
+        let mut entries: Vec<_> = map.iter().collect();
+        entries.reverse();
+        assert_eq!(map.iter().rev().collect::<Vec<_>>(), entries);
+
+        let mut keys: Vec<_> = map.keys().collect();
+        keys.reverse();
+        assert_eq!(map.keys().rev().collect::<Vec<_>>(), keys);
+        assert_eq!(map.values().rev().copied().collect::<Vec<_>>(), keys);
+    }
+
+    #[test]
+    fn test_iter_len() {
+        let mut map: PatriciaTreeMap<u64> = (0..10).map(|key| (key * 11, key)).collect();
+        let mut iter = map.iter();
+        assert_eq!(iter.len(), map.len());
+        iter.next();
+        assert_eq!(iter.len(), map.len() - 1);
+        iter.next_back();
+        assert_eq!(iter.len(), map.len() - 2);
+        assert_eq!(iter.by_ref().count(), map.len() - 2);
+        assert_eq!(iter.len(), 0);
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(map.keys().len(), 10);
+        assert_eq!(map.values().len(), 10);
+        assert_eq!(map.iter_mut().len(), 10);
+        assert_eq!(map.values_mut().skip(3).len(), 7);
+        assert_eq!(map.into_iter().len(), 10);
+    }
+
+    #[test]
+    fn test_iter_lazy() {
+        fn opened(f: impl FnOnce()) -> usize {
+            let before = crate::cursor::OPENED.with(|opened| opened.get());
+            f();
+            crate::cursor::OPENED.with(|opened| opened.get()) - before
+        }
+
+        // With dense keys, no other node can hold a key as small as the first one in range, so
+        // finding it opens just the 13 nodes on the path to it from the root.
+        let map: PatriciaTreeMap<u64> = (0..4096).map(|key| (key, key)).collect();
+        let mut iter = map.iter();
+        assert_eq!(opened(|| assert_eq!(iter.next(), Some((0, &0)))), 13);
+        let mut range = map.range(1000..);
+        assert_eq!(opened(|| assert_eq!(range.next(), Some((1000, &1000)))), 13);
+        let mut entries = map.clone().into_iter();
+        assert_eq!(opened(|| assert_eq!(entries.next(), Some((0, 0)))), 13);
+        assert_eq!(
+            opened(|| assert_eq!(map.iter().count(), 4096)),
+            2 * 4096 - 1
+        );
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut map: PatriciaTreeMap<String> = (0..10).map(|key| (key, key.to_string())).collect();
+        let clone = map.clone();
+        map.insert(3, "x".into());
+        map.insert(100, "y".into());
+        map.remove(5);
+        map.get_mut(7).unwrap().push('z');
+
+        assert_eq!(clone.len(), 10);
+        for (key, value) in clone.iter() {
+            assert_eq!(value, &key.to_string());
+        }
+    }
+
+    #[test]
+    fn test_clone_from() {
+        use super::{InternalNode, Node};
+
+        fn children_ptr(
+            map: &PatriciaTreeMap<String>,
+        ) -> *const (Node<String, u64>, Node<String, u64>) {
+            match &map.root {
+                Some(Node::Internal(InternalNode { children, .. })) => &**children,
+                _ => panic!("root is not an internal node"),
+            }
+        }
+
+        let mut map: PatriciaTreeMap<String> = (0..100).map(|key| (key, key.to_string())).collect();
+        let mut snapshot = map.clone();
+        let ptr = children_ptr(&snapshot);
+
+        map.insert(3, "x".into());
+        map.get_mut(7).unwrap().push('z');
+        snapshot.clone_from(&map);
+        assert_eq!(snapshot, map);
+        assert_eq!(children_ptr(&snapshot), ptr);
+
+        map.remove(5);
+        map.insert(1 << 40, "y".into());
+        snapshot.clone_from(&map);
+        assert_eq!(snapshot, map);
+        assert_eq!(snapshot.len(), 100);
+
+        snapshot.clone_from(&PatriciaTreeMap::new());
+        assert!(snapshot.is_empty());
+        assert_eq!(snapshot.iter().next(), None);
+    }
+
+    #[test]
+    fn test_flat() {
+        let map = PatriciaTreeMap::from([(0b01, "a"), (0b11, "b"), (0b10, "c")]);
+        let flat = map.to_flat();
+        assert_eq!(
+            flat,
+            FlatPatricia {
+                is_leaf: vec![false, true, false, true, true],
+                branch_bits: vec![0, 0, 1, 0, 0],
+                prefixes: vec![0, 0b10, 1, 0b01, 0b11],
+                right_children: vec![2, 0, 4, 0, 0],
+                values: vec!["c", "a", "b"],
+            }
+        );
+        assert_eq!(PatriciaTreeMap::from_flat(flat), map);
+
+        let empty = PatriciaTreeMap::<()>::new().to_flat();
+        assert!(empty.is_leaf.is_empty());
+        assert!(PatriciaTreeMap::from_flat(empty).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "does not belong below it")]
+    fn test_from_flat_invalid() {
+        let mut flat = PatriciaTreeMap::from([(0b01, "a"), (0b11, "b"), (0b10, "c")]).to_flat();
+        flat.prefixes.swap(3, 4);
+        PatriciaTreeMap::from_flat(flat);
+    }
+
+    #[test]
+    fn test_eq() {
+        let a: PatriciaTreeMap<u64> = (0..10).map(|key| (key, key)).collect();
+        let mut b: PatriciaTreeMap<u64> = (0..10).rev().map(|key| (key, key)).collect();
+        assert_eq!(a, b);
+        b.insert(3, 4);
+        assert_ne!(a, b);
+        b.insert(3, 3);
+        b.insert(10, 10);
+        assert_ne!(a, b);
+        b.remove(10);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_ord() {
+        let a = PatriciaTreeMap::from([(1, "a")]);
+        let b = PatriciaTreeMap::from([(1, "b")]);
+        let c = PatriciaTreeMap::from([(1, "a"), (2, "x")]);
+        let d = PatriciaTreeMap::from([(0, "z")]);
+        assert!(a < b);
+        assert!(a < c);
+        assert!(c < b);
+        assert!(d < a);
+        assert_eq!(a.cmp(&a.clone()), std::cmp::Ordering::Equal);
+
+        let mut maps = vec![
+            b.clone(),
+            c.clone(),
+            PatriciaTreeMap::new(),
+            a.clone(),
+            d.clone(),
+        ];
+        maps.sort();
+        assert_eq!(maps, [PatriciaTreeMap::new(), d, a, c, b]);
+    }
+
+    #[test]
+    fn test_debug() {
+        let map = PatriciaTreeMap::from([(2, "b"), (1, "a"), (1 << 40, "c")]);
+        assert_eq!(
+            format!("{:?}", map),
+            r#"{1: "a", 2: "b", 1099511627776: "c"}"#
+        );
+        assert_eq!(format!("{:?}", PatriciaTreeMap::<()>::new()), "{}");
+        assert!(format!("{:?}", map.debug_tree()).contains("branch_bit"));
+    }
+
+    #[test]
+    fn test_patricia_map_macro() {
+        let empty: PatriciaTreeMap<&str> = crate::patricia_map! {};
+        assert!(empty.is_empty());
+        let map = crate::patricia_map! { 1 => "a", 2 => "b" };
+        assert_eq!(map, PatriciaTreeMap::from([(1, "a"), (2, "b")]));
+        let map = crate::patricia_map! {
+            1 << 40 => "c",
+            1 => "a",
+            1 => "b",
+        };
+        assert_eq!(
+            map.into_iter().collect::<Vec<_>>(),
+            [(1, "b"), (1 << 40, "c")]
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        fn hash_of(map: &PatriciaTreeMap<u64>) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            map.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let a: PatriciaTreeMap<u64> = (0..10).map(|key| (key << 20, key)).collect();
+        let mut b: PatriciaTreeMap<u64> = (0..10).rev().map(|key| (key << 20, key)).collect();
+        b.insert(1 << 40, 0);
+        b.remove(1 << 40);
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+    }
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let mut map = PatriciaTreeMap::<&str>::new();
+        assert_eq!(map.longest_prefix_match(0b1000), None);
+
+        map.insert(0b1, "odd");
+        map.insert(0b101, "5 mod 8");
+        map.insert(0b1_0000_0000, "256");
+        assert_eq!(map.longest_prefix_match(0b1000), None);
+
+        map.insert(0b000, "default");
+        assert_eq!(map.longest_prefix_match(0b1000), Some((0b000, &"default")));
+        assert_eq!(map.longest_prefix_match(0b1101), Some((0b101, &"5 mod 8")));
+        assert_eq!(map.longest_prefix_match(0b0011), Some((0b1, &"odd")));
+        assert_eq!(map.longest_prefix_match(0b101), Some((0b101, &"5 mod 8")));
+        assert_eq!(
+            map.longest_prefix_match(0b11_0000_0000),
+            Some((0b1_0000_0000, &"256"))
+        );
+        assert_eq!(
+            map.longest_prefix_match(0b10_0000_0000),
+            Some((0b000, &"default"))
+        );
+
+        // The path to 0b1111 turns right twice. The left sibling at the deeper turn does not
+        // hold a prefix, but the one at the shallower turn does.
+        let map: PatriciaTreeMap<&str> =
+            [(0b1, "odd"), (0b11011, ""), (0b10111, ""), (0b100111, "")]
+                .into_iter()
+                .collect();
+        assert_eq!(map.longest_prefix_match(0b1111), Some((0b1, &"odd")));
+    }
+
+    #[test]
+    fn test_longest_prefix_match_len() {
+        let mut map = PatriciaTreeMap::<&str>::new();
+        map.insert(0b0, "default");
+        assert_eq!(
+            map.longest_prefix_match_len(0b1101),
+            Some((0b0, 0, &"default"))
+        );
+
+        map.insert(0b1, "odd");
+        assert_eq!(map.longest_prefix_match_len(0b1101), Some((0b1, 1, &"odd")));
+
+        map.insert(0b101, "5 mod 8");
+        assert_eq!(
+            map.longest_prefix_match_len(0b1101),
+            Some((0b101, 3, &"5 mod 8"))
+        );
+        assert_eq!(map.longest_prefix_match_len(0b1011), Some((0b1, 1, &"odd")));
+        assert_eq!(
+            map.longest_prefix_match_len(0b1000),
+            Some((0b0, 0, &"default"))
+        );
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let map: PatriciaTreeMap<&str> = [
+            (0b0, "default"),
+            (0b1, "odd"),
+            (0b101, "5 mod 8"),
+            (0b1101, "13 mod 16"),
+            (0b1_1101, "29 mod 32"),
+            (0b11, "3 mod 4"),
+        ]
+        .into();
+        assert_eq!(
+            map.ancestors(0b1101, 64).collect::<Vec<_>>(),
+            [
+                (0b0, &"default"),
+                (0b1, &"odd"),
+                (0b101, &"5 mod 8"),
+                (0b1101, &"13 mod 16")
+            ]
+        );
+        assert_eq!(
+            map.ancestors(0b1_1101, 3).collect::<Vec<_>>(),
+            [(0b0, &"default"), (0b1, &"odd"), (0b101, &"5 mod 8")]
+        );
+        assert_eq!(
+            map.ancestors(0b10, 64).collect::<Vec<_>>(),
+            [(0b0, &"default")]
+        );
+        assert_eq!(
+            PatriciaTreeMap::<()>::new().ancestors(0b1101, 64).next(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_descendants() {
+        // Low 16 bits `0xa8c0` play the role of a /16, with a /24 for each third byte.
+        let map: PatriciaTreeMap<u64> = (0..8)
+            .map(|i| (i << 16 | 0xa8c0, i))
+            .chain([(0xa8c1, 100), (0x01_a9c0, 101), (0xa8c0 ^ 1 << 15, 102)])
+            .collect();
+        assert_eq!(
+            map.descendants(0xa8c0, 16)
+                .map(|(k, v)| (k, *v))
+                .collect::<Vec<_>>(),
+            (0..8).map(|i| (i << 16 | 0xa8c0, i)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            map.descendants(0x03_a8c0, 24).collect::<Vec<_>>(),
+            [(0x03_a8c0, &3)]
+        );
+        assert_eq!(map.descendants(0x08_a8c0, 24).next(), None);
+        assert_eq!(map.descendants(0, 0).count(), map.len());
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let (map, reference) = from_keys((0..1000).map(|key| (key * 7919) ^ (key << 40)).collect());
+        let mut pages = Vec::new();
+        let mut start = 0;
+        loop {
+            let page: Vec<_> = map.iter_from(start).take(64).collect();
+            let Some(&(last, _)) = page.last() else {
+                break;
+            };
+            pages.push(page);
+            match last.checked_add(1) {
+                Some(next) => start = next,
+                None => break,
+            }
+        }
+        assert_eq!(pages.len(), 16);
+        assert!(pages
+            .into_iter()
+            .flatten()
+            .eq(reference.iter().map(|(&k, v)| (k, v))));
+
+        assert_eq!(map.iter_from(u64::MAX).next(), None);
+        assert!(map.iter_from(0).eq(map.iter()));
+
+        // Each page opens at most the 13 nodes on the path to each of its entries, however far
+        // into the map it starts.
+        let map: PatriciaTreeMap<u64> = (0..4096).map(|key| (key, key)).collect();
+        for start in (0..4096).step_by(64) {
+            let before = crate::cursor::OPENED.with(|opened| opened.get());
+            let page: Vec<_> = map.iter_from(start).take(64).collect();
+            let opened = crate::cursor::OPENED.with(|opened| opened.get()) - before;
+            assert!(page.into_iter().eq(map.range(start..start + 64)));
+            assert!(opened <= 64 * 13, "page at {start} opened {opened} nodes");
+        }
+    }
+
+    #[test]
+    fn test_range_mut() {
+        let mut map: PatriciaTreeMap<u64> = (0..100).map(|key| (key * 7, key)).collect();
+        for (_, value) in map.range_mut(70..140) {
+            *value += 1000;
+        }
+        for (key, &value) in map.iter() {
+            if (70..140).contains(&key) {
+                assert_eq!(value, key / 7 + 1000);
+            } else {
+                assert_eq!(value, key / 7);
+            }
+        }
+        assert_eq!(map.range_mut(1000..).count(), 0);
+        assert_eq!(
+            map.range_mut(..=7).map(|(key, _)| key).collect::<Vec<_>>(),
+            [0, 7]
+        );
+    }
+
+    #[test]
+    fn test_range_edges() {
+        let map: PatriciaTreeMap<()> = [0, 150, 199, 200, u64::MAX]
+            .iter()
+            .map(|&k| (k, ()))
+            .collect();
+        assert_eq!(
+            map.range(100..200).map(|(k, _)| k).collect::<Vec<_>>(),
+            vec![150, 199]
+        );
+        assert_eq!(map.range(..0).next(), None);
+        assert_eq!(
+            map.range((Bound::Excluded(u64::MAX), Bound::Unbounded))
+                .next(),
+            None
+        );
+        assert_eq!(
+            map.range(u64::MAX..).map(|(k, _)| k).collect::<Vec<_>>(),
+            vec![u64::MAX]
+        );
+    }
+
+    #[test]
+    fn test_prefix_iter() {
+        let keys = [
+            0b101,
+            0b1101,
+            0b1_0101,
+            0b100,
+            0b111,
+            0b1,
+            0b1_1000_0101,
+            1 << 63 | 0b101,
+        ];
+        let map: PatriciaTreeMap<()> = keys.iter().map(|&k| (k, ())).collect();
+
+        let mut expected: Vec<_> = keys
+            .iter()
+            .copied()
+            .filter(|k| k & 0b111 == 0b101)
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(
+            map.prefix_iter(0b101, 3)
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            map.prefix_iter(0b1111_0101, 3)
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>(),
+            expected
+        );
+
+        assert_eq!(map.prefix_iter(0b1, 1).count(), 7);
+        assert_eq!(map.prefix_iter(0, 0).count(), keys.len());
+        assert_eq!(map.prefix_iter(0b0101, 4).count(), 4);
+        assert_eq!(map.prefix_iter(0b110, 3).count(), 0);
+        assert_eq!(
+            map.prefix_iter(0b1101, 64)
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>(),
+            vec![0b1101]
+        );
+        assert_eq!(map.prefix_iter(0b1100, 64).count(), 0);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut map: PatriciaTreeMap<u64> = (0..100).map(|key| (key * 3, key)).collect();
+        map.retain(|key, value| {
+            *value += 1;
+            key % 2 == 0
+        });
+        assert_eq!(map.len(), 50);
+        assert_eq!(
+            map.iter().map(|(k, v)| (k, *v)).collect::<Vec<_>>(),
+            (0..100)
+                .filter(|key| key % 2 == 0)
+                .map(|key| (key * 3, key + 1))
+                .collect::<Vec<_>>()
+        );
+
+        map.retain(|_, _| false);
+        assert!(map.is_empty());
+        assert_eq!(map.iter().next(), None);
+    }
+
+    #[test]
+    fn test_extract_if() {
+        let (mut map, mut btree) = from_keys((0..50).map(|key| key * 7).collect());
+        let mut odd: Vec<_> = map.extract_if(|key, _| key % 2 == 1).collect();
+        odd.sort();
+        assert_eq!(
+            odd,
+            btree
+                .extract_if(.., |key, _| key % 2 == 1)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(map.len(), 25);
+        #[cfg(debug_assertions)]
+        map.check_invariants();
+        assert!(map
+            .iter()
+            .map(|(key, value)| (key, value.clone()))
+            .eq(btree));
+
+        assert_eq!(
+            map.extract_if(|_, _| true).next().map(|(key, _)| key),
+            Some(0)
+        );
+        assert_eq!(map.len(), 24);
+        assert_eq!(map.first_key_value().map(|(key, _)| key), Some(14));
+
+        let before = super::DESCENTS.with(|descents| descents.get());
+        assert_eq!(map.extract_if(|_, _| true).count(), 24);
+        assert_eq!(super::DESCENTS.with(|descents| descents.get()) - before, 24);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_retain_mut() {
+        let mut map: PatriciaTreeMap<String> = [(1, "a"), (2, ""), (3, "bc"), (4, ""), (5, "d")]
+            .map(|(k, v)| (k, v.to_string()))
+            .into();
+        map.retain_mut(|_, value| {
+            value.make_ascii_uppercase();
+            !value.is_empty()
+        });
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            [
+                (1, &"A".to_string()),
+                (3, &"BC".to_string()),
+                (5, &"D".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut map: PatriciaTreeMap<u64> = (0..10).map(|key| (key, key)).collect();
+        let mut drain = map.drain();
+        assert_eq!(drain.next(), Some((0, 0)));
+        assert_eq!(drain.next_back(), Some((9, 9)));
+        drop(drain);
+        assert!(map.is_empty());
+        assert_eq!(map.get(5), None);
+
+        map.insert(1, 1);
+        assert_eq!(map.drain().collect::<Vec<_>>(), vec![(1, 1)]);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_pop_first_last() {
+        let keys = [5, 1000, 3, 1 << 40, 0, 17, u64::MAX];
+        let mut map: PatriciaTreeMap<u64> = keys.iter().map(|&key| (key, !key)).collect();
+        let mut sorted = keys.to_vec();
+        sorted.sort_unstable();
+
+        assert_eq!(map.pop_last(), Some((u64::MAX, 0)));
+        sorted.pop();
+        let mut popped = vec![];
+        while let Some((key, value)) = map.pop_first() {
+            assert_eq!(value, !key);
+            popped.push(key);
+        }
+        assert_eq!(popped, sorted);
+        assert!(map.is_empty());
+        assert_eq!(map.pop_last(), None);
+    }
+
+    #[test]
+    fn test_first_last_key_value() {
+        let mut map = PatriciaTreeMap::<&str>::new();
+        assert_eq!(map.first_key_value(), None);
+        assert_eq!(map.last_key_value(), None);
+
+        map.extend([(6, "a"), (1 << 50, "b"), (3, "c"), (9, "d")]);
+        assert_eq!(map.first_key_value(), Some((3, &"c")));
+        assert_eq!(map.last_key_value(), Some((1 << 50, &"b")));
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.pop_first(), Some((3, "c")));
+        assert_eq!(map.first_key_value(), Some((6, &"a")));
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn test_first_last_entry() {
+        let mut map = PatriciaTreeMap::<String>::new();
+        assert!(map.first_entry().is_none());
+        assert!(map.last_entry().is_none());
+
+        map.extend([(6, "a"), (1 << 50, "b"), (3, "c"), (9, "d")].map(|(k, v)| (k, v.to_string())));
+        let mut first = map.first_entry().unwrap();
+        assert_eq!(first.key(), 3);
+        first.get_mut().push('!');
+        let last = map.last_entry().unwrap();
+        assert_eq!(last.key(), 1 << 50);
+        assert_eq!(last.remove(), "b");
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[3], "c!");
+        assert_eq!(map.last_key_value(), Some((9, &"d".to_string())));
+        while let Some(entry) = map.first_entry() {
+            entry.remove();
+        }
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_remove_entry() {
+        let mut map: PatriciaTreeMap<&str> = [(4, "a"), (12, "b")].into();
+        assert_eq!(map.remove_entry(12), Some((12, "b")));
+        assert_eq!(map.remove_entry(12), None);
+        assert_eq!(map.remove_entry(8), None);
+        assert_eq!(map.remove_entry(4), Some((4, "a")));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_remove_all() {
+        let mut map: PatriciaTreeMap<()> = (0..1000).map(|key| (key * 3, ())).collect();
+        // Only the multiples of 3 are present, and 999 is listed twice.
+        assert_eq!(map.remove_all((900..1000).chain([999, 5000])), 34);
+        assert_eq!(map.len(), 966);
+        assert!(!map.contains_key(900));
+        assert!(map.contains_key(1002));
+        assert_eq!(map.remove_all([]), 0);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_contains_key() {
+        let (map, _) = from_keys(vec![5, 1000, 3, 1 << 40, 0, u64::MAX]);
+        for key in [
+            5,
+            1000,
+            3,
+            1 << 40,
+            0,
+            u64::MAX,
+            1,
+            4,
+            1 << 41,
+            u64::MAX - 1,
+        ] {
+            assert_eq!(map.contains_key(key), map.contains(key));
+            assert_eq!(map.contains_key(key), map.get(key).is_some());
+        }
+        assert!(!PatriciaTreeMap::<()>::new().contains_key(0));
+    }
+
+    #[test]
+    fn test_entry_descends_once() {
+        fn descents(f: impl FnOnce()) -> usize {
+            let before = super::DESCENTS.with(|descents| descents.get());
+            f();
+            super::DESCENTS.with(|descents| descents.get()) - before
+        }
+
+        let mut map: PatriciaTreeMap<u64> = (0..100).map(|key| (key * 37, key)).collect();
+        for key in [37, 38] {
+            let n = descents(|| {
+                map.entry(key).and_modify(|value| *value += 1).or_insert(0);
+            });
+            assert_eq!(n, 1);
+        }
+        assert_eq!(map[37], 2);
+        assert_eq!(map[38], 0);
+
+        let n = descents(|| {
+            let value = map.get(74).copied().unwrap_or(0);
+            map.insert(74, value + 1);
+        });
+        assert_eq!(n, 2);
+    }
+
+    #[test]
+    fn test_remove_prefix() {
+        let keys = [
+            0b0101,
+            0b1101,
+            0b1_0101,
+            0b0001,
+            0b0111,
+            0b1110,
+            1 << 63 | 0b101,
+        ];
+        let mut map: PatriciaTreeMap<()> = keys.iter().map(|&k| (k, ())).collect();
+
+        assert_eq!(map.remove_prefix(0b101, 3), 4);
+        assert_eq!(map.keys().collect::<Vec<_>>(), [0b0001, 0b0111, 0b1110]);
+        assert_eq!(map.remove_prefix(0b101, 3), 0);
+        assert_eq!(map.remove_prefix(0b0111, 64), 1);
+        assert_eq!(map.remove_prefix(0b11, 2), 0);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.remove_prefix(0, 0), 2);
+        assert!(map.is_empty());
+        assert_eq!(map.remove_prefix(0, 0), 0);
+    }
+
+    #[test]
+    fn test_remove_prefix_longer_than_key() {
+        let mut map: PatriciaTreeMap<()> = [(5, ()), (7, ()), (1 << 40 | 5, ())].into();
+        assert_eq!(map.remove_prefix(6, 100), 0);
+        assert_eq!(map.remove_prefix(5, 100), 1);
+        assert_eq!(map.keys().collect::<Vec<_>>(), [7, 1 << 40 | 5]);
+        assert_eq!(map.remove_prefix(1 << 40 | 5, 255), 1);
+        assert_eq!(map.remove_prefix(7, 65), 1);
+        assert!(map.is_empty());
+
+        let mut map = PatriciaTreeMap::<(), u32>::default();
+        map.insert(5, ());
+        map.insert(6, ());
+        assert_eq!(map.remove_prefix(6, 33), 1);
+        assert_eq!(map.keys().collect::<Vec<_>>(), [5]);
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let mut map = PatriciaTreeMap::new();
+        assert_eq!(map.common_prefix(), None);
+
+        map.insert(0b1011_0000_1010, ());
+        assert_eq!(map.common_prefix(), Some((0b1011_0000_1010, 64)));
+
+        map.extend([(0b0111_1010, ()), (0b1_1010, ()), (0b0110_1010, ())]);
+        assert_eq!(map.common_prefix(), Some((0b1010, 4)));
+
+        map.insert(0b1011, ());
+        assert_eq!(map.common_prefix(), Some((0b0, 0)));
+    }
+
+    #[test]
+    fn test_get_key_value() {
+        let mut map = PatriciaTreeMap::<&str>::new();
+        assert_eq!(map.get_key_value(3), None);
+        map.insert(3, "a");
+        map.insert(7, "b");
+        assert_eq!(map.get_key_value(3), Some((3, &"a")));
+        assert_eq!(map.get_key_value(7), Some((7, &"b")));
+        assert_eq!(map.get_key_value(5), None);
+    }
+
+    #[test]
+    fn test_index() {
+        let map: PatriciaTreeMap<&str> = vec![(1, "a"), (2, "b")].into_iter().collect();
+        assert_eq!(map[1], "a");
+        assert_eq!(map[2], "b");
+    }
+
+    #[test]
+    #[should_panic(expected = "key 3 not found")]
+    fn test_index_missing() {
+        let map: PatriciaTreeMap<&str> = vec![(1, "a"), (2, "b")].into_iter().collect();
+        let _ = map[3];
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "keys are not in ascending order")]
+    fn test_from_sorted_iter_unsorted() {
+        PatriciaTreeMap::from_sorted_iter([(2u64, ()), (1, ())]);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let map: PatriciaTreeMap<&str> =
+            vec![(1, "a"), (3, "b"), (2, "\"c\"")].into_iter().collect();
+        let dot = map.to_dot();
+        assert!(dot.starts_with("digraph {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("label=\"key_prefix: 0\\nbranch_bit: 0\""));
+        assert!(dot.contains("label=\"key_prefix: 1\\nbranch_bit: 1\""));
+        assert!(dot.contains("label=\"key: 1\\nvalue: a\""));
+        assert!(dot.contains("label=\"key: 3\\nvalue: b\""));
+        assert!(dot.contains("label=\"key: 2\\nvalue: \\\"c\\\"\""));
+        assert_eq!(dot.matches(" -> ").count(), 4);
+
+        assert_eq!(PatriciaTreeMap::<&str>::new().to_dot(), "digraph {\n}\n");
+    }
+
+    #[test]
+    fn test_merge_with() {
+        let mut counts: PatriciaTreeMap<u32> = vec![(1, 3), (2, 1), (7, 4)].into_iter().collect();
+        let other: PatriciaTreeMap<u32> = vec![(2, 5), (3, 2), (7, 1), (1 << 40, 9)]
+            .into_iter()
+            .collect();
+        counts.merge_with(other, |count, other| *count += other);
+        assert_eq!(
+            counts.into_iter().collect::<Vec<_>>(),
+            vec![(1, 3), (2, 6), (3, 2), (7, 5), (1 << 40, 9)]
+        );
+    }
+
+    #[test]
+    fn test_append() {
+        let mut map: PatriciaTreeMap<&str> = vec![(1, "a"), (2, "b")].into_iter().collect();
+        let mut other: PatriciaTreeMap<&str> = vec![(3, "c"), (1 << 40, "d")].into_iter().collect();
+        map.append(&mut other);
+        assert!(other.is_empty());
+        assert_eq!(other.iter().next(), None);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            vec![(1, &"a"), (2, &"b"), (3, &"c"), (1 << 40, &"d")]
+        );
+
+        let mut other: PatriciaTreeMap<&str> = vec![(2, "x"), (5, "y")].into_iter().collect();
+        map.append(&mut other);
+        assert!(other.is_empty());
+        assert_eq!(map.len(), 5);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            vec![(1, &"a"), (2, &"x"), (3, &"c"), (5, &"y"), (1 << 40, &"d")]
+        );
+    }
+
+    #[test]
+    fn test_get_disjoint_mut() {
+        let mut map: PatriciaTreeMap<String> =
+            (0..100).map(|key| (key * 37, key.to_string())).collect();
+        match map.get_disjoint_mut([37, 74]) {
+            [Some(a), Some(b)] => std::mem::swap(a, b),
+            _ => panic!("missing values"),
+        }
+        assert_eq!(map[37], "2");
+        assert_eq!(map[74], "1");
+
+        let [a, b, c] = map.get_disjoint_mut([0, 1, 3663]);
+        assert_eq!(a.map(|value| value.as_str()), Some("0"));
+        assert_eq!(b, None);
+        assert_eq!(c.map(|value| value.as_str()), Some("99"));
+
+        assert_eq!(
+            PatriciaTreeMap::<String>::new().get_disjoint_mut([1, 2]),
+            [None, None]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate key 74")]
+    fn test_get_disjoint_mut_duplicate_keys() {
+        let mut map: PatriciaTreeMap<u64> = (0..100).map(|key| (key * 37, key)).collect();
+        map.get_disjoint_mut([37, 74, 74]);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let map: PatriciaTreeMap<u64> = (0..100).map(|key| (key * 37, key)).collect();
+        assert_eq!(
+            map.get_many(&[74, 1, 3663, 74, 0, u64::MAX]),
+            [Some(&2), None, Some(&99), Some(&2), Some(&0), None]
+        );
+        assert_eq!(map.get_many(&[]), []);
+        assert_eq!(
+            PatriciaTreeMap::<u64>::new().get_many(&[1, 2]),
+            [None, None]
+        );
+    }
+
+    #[test]
+    fn test_swap_values() {
+        let mut map = PatriciaTreeMap::from([(1, "a"), (2, "b"), (1 << 40, "c")]);
+        assert!(map.swap_values(1, 1 << 40));
+        assert_eq!(
+            map.into_iter().collect::<Vec<_>>(),
+            [(1, "c"), (2, "b"), (1 << 40, "a")]
+        );
+
+        let mut map = PatriciaTreeMap::from([(1, "a"), (2, "b")]);
+        assert!(map.swap_values(2, 2));
+        assert!(!map.swap_values(1, 3));
+        assert!(!map.swap_values(3, 2));
+        assert!(!map.swap_values(3, 3));
+        assert_eq!(map, PatriciaTreeMap::from([(1, "a"), (2, "b")]));
+    }
+
+    #[test]
+    fn test_map_values() {
+        let keys = [5, 1000, 3, 1 << 40, 0, 17];
+        let map: PatriciaTreeMap<String> = keys
+            .iter()
+            .map(|&key| (key, "x".repeat(key as usize % 7)))
+            .collect();
+        let lengths = map.map_values(String::len);
+        assert_eq!(lengths.len(), keys.len());
+        for &key in keys.iter() {
+            assert_eq!(lengths.get(key), Some(&(key as usize % 7)));
+        }
+        assert!(map.keys().eq(lengths.keys()));
+        assert_eq!(
+            PatriciaTreeMap::<String>::new()
+                .map_values(String::len)
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_filter_map_values() {
+        let map: PatriciaTreeMap<&str> =
+            vec![(1, "12"), (2, "x"), (3, "-4"), (1 << 40, "7"), (5, "")]
+                .into_iter()
+                .collect();
+        let numbers = map.filter_map_values(|_, value| value.parse::<i32>().ok());
+        assert_eq!(
+            numbers.into_iter().collect::<Vec<_>>(),
+            vec![(1, 12), (3, -4), (1 << 40, 7)]
+        );
+
+        let none = map.filter_map_values(|_, _| None::<()>);
+        assert!(none.is_empty());
+        let odd_keys = map.filter_map_values(|key, value| (key % 2 == 1).then_some(value.len()));
+        assert_eq!(
+            odd_keys.into_iter().collect::<Vec<_>>(),
+            vec![(1, 2), (3, 2), (5, 0)]
+        );
+    }
+
+    #[test]
+    fn test_height() {
+        let mut map = PatriciaTreeMap::new();
+        assert_eq!(map.height(), 0);
+        assert!(map.depth_histogram().is_empty());
+
+        map.insert(0, ());
+        assert_eq!(map.height(), 1);
+        assert_eq!(map.depth_histogram(), vec![1]);
+
+        for key in 1..4 {
+            map.insert(key, ());
+        }
+        assert_eq!(map.height(), 3);
+        assert_eq!(map.depth_histogram(), vec![0, 0, 4]);
+
+        // 4 shares the low two bits with 0, so they are split one level further down.
+        map.insert(4, ());
+        assert_eq!(map.height(), 4);
+        assert_eq!(map.depth_histogram(), vec![0, 0, 3, 2]);
+
+        // Keys with a single set bit form a chain.
+        let map: PatriciaTreeMap<()> = (0..10).map(|bit| (1 << bit, ())).chain([(0, ())]).collect();
+        assert_eq!(map.height(), 11);
+        assert_eq!(map.depth_histogram(), vec![0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "is on the wrong side of branch bit")]
+    fn test_check_invariants_swapped_children() {
+        use super::{InternalNode, Node};
+
+        let mut map: PatriciaTreeMap<()> = vec![(1, ()), (2, ())].into_iter().collect();
+        map.check_invariants();
+        if let Some(Node::Internal(InternalNode { children, .. })) = map.root.as_mut() {
+            let (left, right) = &mut **children;
+            std::mem::swap(left, right);
+        }
+        map.check_invariants();
+    }
+
+    #[test]
+    fn test_remove_collapses_internal_node() {
+        use super::{InternalNode, LeafNode, Node};
+
+        // 0 and 2 branch on bit 1 below the left child of the root, which branches on bit 0.
+        let mut map: PatriciaTreeMap<()> = [(0, ()), (2, ()), (1, ())].into();
+        assert_eq!(map.node_counts(), (2, 3));
+        assert_eq!(map.remove(2), Some(()));
+        #[cfg(debug_assertions)]
+        map.check_invariants();
+        assert_eq!(map.node_counts(), (1, 2));
+        match map.root.as_ref() {
+            Some(Node::Internal(InternalNode {
+                branch_bit: 0,
+                children,
+                ..
+            })) => {
+                assert!(matches!(children.0, Node::Leaf(LeafNode { key: 0, .. })));
+                assert!(matches!(children.1, Node::Leaf(LeafNode { key: 1, .. })));
+            }
+            _ => panic!("expected the root to branch on bit 0"),
+        }
+
+        assert_eq!(map.remove(0), Some(()));
+        assert_eq!(map.node_counts(), (0, 1));
+        assert!(matches!(
+            map.root,
+            Some(Node::Leaf(LeafNode { key: 1, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_node_counts() {
+        let mut map = PatriciaTreeMap::<u64>::new();
+        assert_eq!(map.node_counts(), (0, 0));
+        for key in 0..200 {
+            map.insert(key * 37 % 101, key);
+            let (internal_nodes, leaf_nodes) = map.node_counts();
+            assert_eq!(leaf_nodes, map.len());
+            assert_eq!(internal_nodes, map.len() - 1);
+            assert_eq!(map.allocated_nodes(), internal_nodes + leaf_nodes);
+        }
+        map.retain(|key, _| key % 3 != 0);
+        for key in (0..101).step_by(5) {
+            map.remove(key);
+            let (internal_nodes, leaf_nodes) = map.node_counts();
+            assert_eq!(leaf_nodes, map.len());
+            assert_eq!(internal_nodes, map.len() - 1);
+        }
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut map = PatriciaTreeMap::<String>::new();
+        assert_eq!(map.memory_usage(), 0);
+        // A single leaf is stored in the map itself.
+        map.insert(0, String::new());
+        assert_eq!(map.memory_usage(), 0);
+        let mut previous = 0;
+        for key in 1..100 {
+            map.insert(key * 37, "x".repeat(key as usize));
+            let usage = map.memory_usage();
+            assert!(usage > previous);
+            assert!(map.memory_usage_with(String::capacity) >= usage + key as usize);
+            previous = usage;
+        }
+        map.insert(0, "y".into());
+        assert_eq!(map.memory_usage(), previous);
+    }
+
+    #[test]
+    fn test_inline_leaves() {
+        use super::Node;
+        use std::mem::size_of;
+
+        let map: PatriciaTreeMap<()> = (0..1000).map(|key| (key * 37, ())).collect();
+        assert_eq!(map.node_counts(), (999, 1000));
+        assert_eq!(map.memory_usage(), 999 * 2 * size_of::<Node<(), u64>>());
+
+        // Boxing every node on its own would take an allocation per node, each large enough for
+        // a prefix, a branch bit and two child pointers.
+        let boxed_nodes = 1999 * size_of::<(u64, u8, Box<()>, Box<()>)>();
+        assert!(map.memory_usage() < boxed_nodes);
+    }
+
+    #[test]
+    fn test_drop_deep_tree() {
+        // Keys with a single set bit give a chain with one level per bit.
+        let mut map = PatriciaTreeMap::<(), u128>::default();
+        map.insert(0, ());
+        for bit in 0..128 {
+            map.insert(1 << bit, ());
+        }
+        // A partly consumed iterator drops the rest of the tree the same way.
+        let mut entries = map.clone().into_iter();
+        assert_eq!(entries.next(), Some((0, ())));
+        std::thread::Builder::new()
+            .stack_size(16 * 1024)
+            .spawn(move || {
+                drop(map);
+                drop(entries);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_u128_keys() {
+        let keys = [0, 1, 1 << 64, (1 << 64) | 1, 1 << 127, u128::MAX, 12345];
+        let mut map = PatriciaTreeMap::<u128, u128>::default();
+        for &key in keys.iter() {
+            assert_eq!(map.insert(key, key / 2), None);
+        }
+        assert_eq!(map.len(), keys.len());
+        for &key in keys.iter() {
+            assert_eq!(map.get(key), Some(&(key / 2)));
+        }
+        assert_eq!(map.get(1 << 65), None);
+        assert_eq!(PatriciaTreeMap::min(&map), Some((0, &0)));
+        assert_eq!(
+            PatriciaTreeMap::max(&map),
+            Some((u128::MAX, &(u128::MAX / 2)))
+        );
+        assert_eq!(map.ceiling((1 << 64) + 2), Some((1 << 127, &(1 << 126))));
+
+        let mut sorted = keys.to_vec();
+        sorted.sort();
+        assert_eq!(map.keys().collect::<Vec<_>>(), sorted);
+        assert_eq!(
+            map.range(1..=1 << 64).map(|(k, _)| k).collect::<Vec<_>>(),
+            vec![1, 12345, 1 << 64]
+        );
+
+        assert_eq!(map.remove(1 << 64), Some(1 << 63));
+        assert_eq!(map.remove(1 << 64), None);
+        assert_eq!(map.get((1 << 64) | 1), Some(&(1 << 63)));
+        assert_eq!(map.len(), keys.len() - 1);
+    }
+
+    #[test]
+    fn test_insert_return_value() {
+        let mut map = PatriciaTreeMap::<String>::new();
+        assert_eq!(map.get(123), None);
+        assert_eq!(map.insert(123, "A".into()), None);
+        assert_eq!(map.get(123), Some(&"A".into()));
+        assert_eq!(map.insert(123, "B".into()), Some("A".into()));
+        assert_eq!(map.get(123), Some(&"B".into()));
+    }
+
+    #[test]
+    fn test_remove_last() {
+        let mut map = PatriciaTreeMap::<String>::new();
+        map.insert(123, "A".into());
+        assert_eq!(map.remove(456), None);
+        assert_eq!(map.remove(123), Some("A".into()));
+        assert_eq!(map.remove(123), None);
+        assert!(map.is_empty());
+        assert!(map.root.is_none());
+        assert_eq!(map.iter().next(), None);
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut map = PatriciaTreeMap::<Vec<u32>>::new();
+        map.insert(5, vec![1]);
+        assert_eq!(map.get_mut(6), None);
+        map.get_mut(5).unwrap().push(2);
+        assert_eq!(map.get(5), Some(&vec![1, 2]));
+    }
+
+    #[test]
+    fn test_entry() {
+        let keys = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5];
+        let mut map = PatriciaTreeMap::<usize>::new();
+        for &key in keys.iter() {
+            map.entry(key).and_modify(|count| *count += 1).or_insert(1);
+        }
+
+        assert_eq!(map.len(), 7);
+        for key in 0..10 {
+            let count = keys.iter().filter(|&&k| k == key).count();
+            assert_eq!(map.get(key), if count == 0 { None } else { Some(&count) });
+        }
+
+        assert_eq!(map.entry(7).key(), 7);
+        assert_eq!(*map.entry(7).or_insert_with_key(|key| key as usize * 2), 14);
+        assert_eq!(*map.entry(7).or_insert_with(|| 0), 14);
+        match map.entry(5) {
+            Entry::Occupied(entry) => assert_eq!(entry.remove(), 3),
+            Entry::Vacant(_) => unreachable!(),
+        }
+        assert_eq!(map.get(5), None);
+        assert_eq!(map.len(), 7);
+    }
+
+    #[test]
+    fn test_entry_or_default() {
+        let mut groups = PatriciaTreeMap::<Vec<u64>>::new();
+        for value in 0..20 {
+            groups.entry(value % 3).or_default().push(value);
+        }
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0], vec![0, 3, 6, 9, 12, 15, 18]);
+        assert_eq!(groups[1], vec![1, 4, 7, 10, 13, 16, 19]);
+        assert_eq!(groups[2], vec![2, 5, 8, 11, 14, 17]);
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let mut map = PatriciaTreeMap::<String>::new();
+        map.get_or_insert_with(3, || "a".into()).push('b');
+        assert_eq!(map[3], "ab");
+        let value = map.get_or_insert_with(3, || unreachable!());
+        assert_eq!(value, "ab");
+        assert_eq!(map.get_or_insert_with(11, String::new), "");
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_get_or_insert() {
+        let mut map = PatriciaTreeMap::<String>::new();
+        let (value, inserted) = map.get_or_insert(3, "a".into());
+        assert!(inserted);
+        value.push('b');
+        let (value, inserted) = map.get_or_insert(3, "c".into());
+        assert!(!inserted);
+        assert_eq!(value, "ab");
+        assert_eq!(
+            map.get_or_insert(1 << 40, "d".into()),
+            (&mut "d".to_string(), true)
+        );
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn test_try_insert() {
+        let mut map = PatriciaTreeMap::<String>::new();
+        let value = map.try_insert(5, "a".into()).unwrap();
+        assert_eq!(value, "a");
+        value.push('b');
+        assert_eq!(map.try_insert(6, "c".into()).unwrap(), "c");
+
+        let error = map.try_insert(5, "d".into()).unwrap_err();
+        assert_eq!(error.entry.key(), 5);
+        assert_eq!(error.entry.get(), "ab");
+        assert_eq!(error.value, "d");
+        assert_eq!(
+            error.to_string(),
+            "failed to insert \"d\", key 5 already exists with value \"ab\""
+        );
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[5], "ab");
+    }
+
+    #[test]
+    fn test_from() {
+        let map = PatriciaTreeMap::from([(1, "a"), (2, "b"), (1 << 40, "c"), (2, "d")]);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            vec![(1, &"a"), (2, &"d"), (1 << 40, &"c")]
+        );
+        assert_eq!(
+            PatriciaTreeMap::from(vec![(1, "a"), (2, "b"), (2, "d"), (1 << 40, "c")]),
+            map
+        );
+        assert!(PatriciaTreeMap::<()>::from([]).is_empty());
+    }
+
+    #[test]
+    fn test_btree_map_round_trip() {
+        let reference: BTreeMap<u64, String> = [5, 1000, 3, 1 << 40, 0, 17, u64::MAX]
+            .iter()
+            .map(|&key| (key, key.to_string()))
+            .collect();
+        let map = PatriciaTreeMap::from(reference.clone());
+        assert_eq!(map.len(), reference.len());
+        assert!(map
+            .iter()
+            .eq(reference.iter().map(|(&key, value)| (key, value))));
+        assert_eq!(BTreeMap::from(map), reference);
+
+        let map = PatriciaTreeMap::<String, u32>::from(BTreeMap::new());
+        assert!(BTreeMap::from(map).is_empty());
+    }
+
+    #[test]
+    fn test_singleton() {
+        let mut map = PatriciaTreeMap::singleton(7u64, "x");
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(7), Some(&"x"));
+        assert_eq!(map.get(6), None);
+        map.insert(6, "y");
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(6, &"y"), (7, &"x")]);
+    }
+
+    #[test]
+    fn test_node_size() {
+        use super::{InternalNode, Node};
+        use std::mem::size_of;
+
+        // Nodes are restructured in place with `replace_with`, so `Node` needs no placeholder
+        // variant and fits in the space of an internal node.
+        assert_eq!(
+            size_of::<Node<u8, u64>>(),
+            size_of::<InternalNode<u8, u64>>()
+        );
+    }
+
+    #[test]
+    fn test_top_branch_bit() {
+        type Map = PatriciaTreeMap<u64>;
+        assert_eq!(Map::get_prefix(u64::MAX, 63), u64::MAX >> 1);
+        assert_eq!(Map::get_prefix(1 << 63, 63), 0);
+        assert!(Map::is_left(u64::MAX >> 1, 63));
+        assert!(!Map::is_left(1 << 63, 63));
+
+        for (a, b) in [(0, 1 << 63), (u64::MAX >> 1, u64::MAX), (5, 5 | 1 << 63)] {
+            let map: Map = [(b, b), (a, a)].into();
+            #[cfg(debug_assertions)]
+            map.check_invariants();
+            assert_eq!(map.common_prefix(), Some((a, 63)));
+            assert_eq!(map.iter().collect::<Vec<_>>(), [(a, &a), (b, &b)]);
+            assert_eq!(map.get(a), Some(&a));
+            assert_eq!(map.get(b), Some(&b));
+            assert_eq!(map.get(a ^ 1), None);
+            assert_eq!(map.range(a + 1..).next(), Some((b, &b)));
+            assert_eq!(map.floor(b - 1), Some((a, &a)));
+
+            for (removed, kept) in [(a, b), (b, a)] {
+                let mut map = map.clone();
+                assert_eq!(map.remove(removed), Some(removed));
+                assert_eq!(map.remove(removed), None);
+                assert_eq!(map.get(removed), None);
+                assert_eq!(map.iter().collect::<Vec<_>>(), [(kept, &kept)]);
+            }
+        }
+
+        let keys = [0, u64::MAX, 1 << 63, u64::MAX >> 1, 1];
+        for removed in keys {
+            let mut map: Map = keys.iter().map(|&key| (key, key)).collect();
+            assert_eq!(map.remove(removed), Some(removed));
+            #[cfg(debug_assertions)]
+            map.check_invariants();
+            for key in keys {
+                assert_eq!(map.get(key), (key != removed).then_some(&key));
+            }
+        }
+    }
+
+    #[test]
+    fn test_full_width_branch_bit() {
+        type Map<K> = PatriciaTreeMap<(), K>;
+        assert_eq!(Map::<u64>::get_prefix(u64::MAX, 64), u64::MAX);
+        assert!(Map::<u64>::is_left(u64::MAX, 64));
+        assert_eq!(Map::<u32>::get_prefix(u32::MAX, 32), u32::MAX);
+        assert!(Map::<u32>::is_left(u32::MAX, 32));
+        assert_eq!(Map::<u128>::get_prefix(u128::MAX, 127), u128::MAX >> 1);
+        assert!(!Map::<u128>::is_left(u128::MAX, 127));
+        assert_eq!(Map::<u128>::get_prefix(u128::MAX, 128), u128::MAX);
+        assert!(Map::<u128>::is_left(u128::MAX, 128));
+    }
+
+    #[test]
+    fn test_clear() {
+        let drops = Rc::new(());
+        let mut map = PatriciaTreeMap::<Rc<()>>::new();
+        for key in 0..1000 {
+            map.insert(key * 7919, drops.clone());
+        }
+        assert_eq!(Rc::strong_count(&drops), 1001);
+
+        map.clear();
+        assert!(map.is_empty());
+        assert_eq!(Rc::strong_count(&drops), 1);
+        for key in 0..1000 {
+            assert_eq!(map.get(key * 7919), None);
+        }
+    }
+
+    /// An operation of `test_operations_match_hash_map` on the key at an index into a pool of
+    /// keys, so that operations repeat keys even though the keys span all 64 bits.
+    #[derive(Clone, Debug)]
+    enum Operation {
+        Insert(Index, u32),
+        Remove(Index),
+        Get(Index),
+        Contains(Index),
+    }
+
+    fn operation() -> impl Strategy<Value = Operation> {
+        prop_oneof![
+            (any::<Index>(), any::<u32>()).prop_map(|(key, value)| Operation::Insert(key, value)),
+            any::<Index>().prop_map(Operation::Remove),
+            any::<Index>().prop_map(Operation::Get),
+            any::<Index>().prop_map(Operation::Contains),
+        ]
+    }
+
+    /// Keys spanning all 64 bits, biased towards the extremes and towards keys that only differ
+    /// in their highest bits.
+    fn full_width_key() -> impl Strategy<Value = u64> {
+        prop_oneof![
+            any::<u64>(),
+            any::<u64>().prop_map(|key| key << 60),
+            any::<u64>().prop_map(|key| key | u64::MAX >> 4),
+            Just(0),
+            Just(u64::MAX),
+            Just(1 << 63),
+        ]
+    }
+
+    fn unique_vec<T>(element: T, size: impl Into<SizeRange>) -> impl Strategy<Value = Vec<T::Value>>
+    where
+        T: Strategy,
+        T::Value: Hash + Eq,
+    {
+        let x = hash_set(element, size);
+        x.prop_map(|v| v.into_iter().collect())
+    }
+
+    fn from_keys(keys: Vec<u64>) -> (PatriciaTreeMap<String>, BTreeMap<u64, String>) {
+        let mut tree = PatriciaTreeMap::<String>::new();
+        let mut counter = HashMap::<u64, usize>::new();
+        let mut reference = BTreeMap::<u64, String>::new();
+        for key in keys.into_iter() {
+            let count = counter.entry(key).or_insert(0);
+            *count += 1;
+            let value = format!("{}-{}", key, count);
+            tree.insert(key, value.clone());
+            reference.insert(key, value);
+        }
+        (tree, reference)
+    }
+
+    fn test_insertion_impl(keys: Vec<u64>) {
+        let (tree, reference) = from_keys(keys);
+
+        assert_eq!(tree.len(), reference.len());
+
+        for (k, v) in reference.into_iter() {
+            assert_eq!(tree.get(k), Some(&v));
+        }
+    }
+
+    fn test_remove_impl(keys: Vec<u64>) {
+        let (mut tree, reference) = from_keys(keys.clone());
+        let mut reference: HashMap<u64, String> = reference.into_iter().collect();
+
+        let to_remove: Vec<u64> = reference.keys().copied().step_by(2).collect();
+        for key in to_remove {
+            assert_eq!(tree.remove(key), reference.remove(&key));
+            assert_eq!(tree.remove(key), None);
+        }
+
+        // Check the original keys, which are the only ones likely to be present for full-width
+        // keys, both those that were removed and those that remain.
+        assert_eq!(tree.len(), reference.len());
+        for key in keys.into_iter().chain(0..1 << 10) {
+            assert_eq!(tree.get(key), reference.get(&key));
+        }
+    }
+
+    fn test_floor_ceiling_impl(keys: Vec<u64>, queries: Vec<u64>) {
+        let (tree, reference) = from_keys(keys);
+
+        for query in queries {
+            assert_eq!(
+                tree.ceiling(query),
+                reference.range(query..).next().map(|(k, v)| (*k, v))
+            );
+            assert_eq!(
+                tree.floor(query),
+                reference.range(..=query).next_back().map(|(k, v)| (*k, v))
+            );
+        }
+    }
+
+    fn test_nearest_impl(keys: Vec<u64>, queries: Vec<u64>) {
+        let (tree, reference) = from_keys(keys);
+
+        for query in queries {
+            let expected = reference
+                .iter()
+                .min_by_key(|(k, _)| (k.abs_diff(query), **k))
+                .map(|(k, v)| (*k, v));
+            assert_eq!(tree.nearest(query), expected);
+        }
+    }
+
+    fn test_min_max_impl(keys: Vec<u64>) {
+        let (tree, _) = from_keys(keys);
+
+        assert_eq!(PatriciaTreeMap::min(&tree), tree.iter().next());
+        assert_eq!(PatriciaTreeMap::max(&tree), tree.iter().next_back());
+    }
+
+    fn test_range_impl(keys: Vec<u64>, (a, b): (u64, u64)) {
+        let (tree, reference) = from_keys(keys);
+        let (a, b) = (a.min(b), a.max(b));
+
+        fn check(
+            tree: &PatriciaTreeMap<String>,
+            reference: &BTreeMap<u64, String>,
+            range: impl RangeBounds<u64> + Clone,
+        ) {
+            assert_eq!(
+                tree.range(range.clone()).collect::<Vec<_>>(),
+                reference
+                    .range(range)
+                    .map(|(k, v)| (*k, v))
+                    .collect::<Vec<_>>()
+            );
+        }
+
+        check(&tree, &reference, a..b);
+        check(&tree, &reference, a..=b);
+        check(&tree, &reference, ..b);
+        check(&tree, &reference, ..=b);
+        check(&tree, &reference, a..);
+        check(&tree, &reference, ..);
+        check(&tree, &reference, (Bound::Excluded(a), Bound::Included(b)));
+
+        assert_eq!(tree.count_range(a..b), reference.range(a..b).count());
+        assert_eq!(tree.count_range(a..=b), reference.range(a..=b).count());
+        assert_eq!(tree.count_range(..), tree.len());
+        assert_eq!(
+            tree.count_range((Bound::Excluded(a), Bound::Unbounded)),
+            tree.range((Bound::Excluded(a), Bound::Unbounded)).count()
+        );
+
+        // Taking two entries from the front for each from the back, the ends meet in the middle.
+        let mut iter = tree.range(a..=b);
+        let mut expected = reference.range(a..=b).map(|(k, v)| (*k, v));
+        for i in 0.. {
+            let entry = if i % 3 == 2 {
+                (iter.next_back(), expected.next_back())
+            } else {
+                (iter.next(), expected.next())
+            };
+            assert_eq!(entry.0, entry.1);
+            if entry.0.is_none() {
+                break;
+            }
+        }
+        assert_eq!(iter.next(), None);
+
+        let mut tree = tree;
+        assert!(tree
+            .range_mut(a..b)
+            .map(|(k, v)| (k, &*v))
+            .eq(reference.range(a..b).map(|(k, v)| (*k, v))));
+    }
+
+    fn test_retain_impl(keys: Vec<u64>, modulus: u64) {
+        let (mut tree, mut reference) = from_keys(keys);
+        tree.retain(|key, _| key % modulus == 0);
+        reference.retain(|key, _| key % modulus == 0);
+
+        assert_eq!(tree.len(), reference.len());
+        for key in 0..1 << 10 {
+            assert_eq!(tree.get(key), reference.get(&key));
+        }
+    }
+
+    fn test_iter_impl(keys: Vec<u64>) {
+        let (tree, reference) = from_keys(keys);
+
+        let vec = tree.iter().take(tree.len() + 1).collect::<Vec<_>>();
+        assert_eq!(vec.len(), tree.len());
+
+        let vec: Vec<_> = vec.into_iter().map(|(k, v)| (k, v.clone())).collect();
+        assert_eq!(vec, reference.into_iter().collect::<Vec<_>>());
+    }
+
+    fn test_rank_select_impl(keys: Vec<u64>, key: u64) {
+        let (tree, reference) = from_keys(keys);
+        let sorted: Vec<u64> = reference.keys().copied().collect();
+        assert_eq!(tree.rank(key), sorted.iter().filter(|&&k| k < key).count());
+        for (i, &k) in sorted.iter().enumerate() {
+            assert_eq!(tree.rank(k), i);
+            assert_eq!(tree.select(i), Some((k, &reference[&k])));
+        }
+        assert_eq!(tree.select(sorted.len()), None);
+    }
+
+    proptest! {
+        #[test]
+        fn test_insert_with_duplicates(keys in vec(bits::u64::between(0, 10), 0..100)) {
+            test_insertion_impl(keys)
+        }
+
+        #[test]
         fn test_insert_unique(keys in unique_vec(bits::u64::between(0, 10), 0..100)) {
             test_insertion_impl(keys)
         }
 
+        #[test]
+        fn test_remove_with_duplicates(keys in vec(bits::u64::between(0, 10), 0..100)) {
+            test_remove_impl(keys)
+        }
+
+        #[test]
+        fn test_remove_unique(keys in unique_vec(bits::u64::between(0, 10), 0..100)) {
+            test_remove_impl(keys)
+        }
+
+        #[test]
+        fn test_insert_full_width(keys in vec(full_width_key(), 0..100)) {
+            test_insertion_impl(keys)
+        }
+
+        #[test]
+        fn test_remove_full_width(keys in vec(full_width_key(), 0..100)) {
+            test_remove_impl(keys)
+        }
+
+        #[test]
+        fn test_eq_shuffled(
+            (keys, shuffled) in unique_vec(any::<u64>(), 0..100)
+                .prop_flat_map(|keys| (Just(keys.clone()), Just(keys).prop_shuffle()))
+        ) {
+            let a: PatriciaTreeMap<u64> = keys.into_iter().map(|key| (key, !key)).collect();
+            let b: PatriciaTreeMap<u64> = shuffled.into_iter().map(|key| (key, !key)).collect();
+            prop_assert_eq!(a, b);
+        }
+
+        #[test]
+        fn test_ancestors_brute_force(
+            keys in vec(bits::u64::between(0, 10), 0..50),
+            query in bits::u64::between(0, 12),
+            query_len in 0u8..=64,
+        ) {
+            let (tree, _) = from_keys(keys);
+            let query_bits = query.low_bits(query_len as u32);
+            let expected: Vec<_> = tree
+                .iter()
+                .filter(|(key, _)| PatriciaTreeMap::<String>::is_prefix_of(*key, query_bits))
+                .collect();
+            let mut actual: Vec<_> = tree.ancestors(query, query_len).collect();
+            prop_assert!(actual.windows(2).all(|w| w[0].0.significant_bits() < w[1].0.significant_bits()));
+            actual.sort_unstable_by_key(|(key, _)| *key);
+            prop_assert_eq!(actual, expected);
+        }
+
+        #[test]
+        fn test_longest_prefix_match_brute_force(
+            keys in vec(bits::u64::between(0, 10), 0..50),
+            query in bits::u64::between(0, 12),
+        ) {
+            let (tree, _) = from_keys(keys);
+            let expected = tree
+                .iter()
+                .filter(|(key, _)| PatriciaTreeMap::<String>::is_prefix_of(*key, query))
+                .max_by_key(|(key, _)| 64 - key.leading_zeros());
+            prop_assert_eq!(tree.longest_prefix_match(query), expected);
+        }
+
+        #[test]
+        fn test_floor_ceiling(
+            keys in vec(bits::u64::between(0, 10), 0..100),
+            queries in vec(bits::u64::between(0, 11), 0..20),
+        ) {
+            test_floor_ceiling_impl(keys, queries);
+        }
+
+        #[test]
+        fn test_floor_ceiling_full_width(
+            keys in vec(any::<u64>(), 0..100),
+            queries in vec(any::<u64>(), 0..20),
+        ) {
+            let queries = queries.into_iter().chain(keys.iter().copied()).chain([0, u64::MAX]).collect();
+            test_floor_ceiling_impl(keys, queries);
+        }
+
+        #[test]
+        fn test_nearest(
+            keys in vec(bits::u64::between(0, 10), 0..100),
+            queries in vec(bits::u64::between(0, 11), 0..20),
+        ) {
+            test_nearest_impl(keys, queries);
+        }
+
+        #[test]
+        fn test_nearest_full_width(
+            keys in vec(any::<u64>(), 0..100),
+            queries in vec(any::<u64>(), 0..20),
+        ) {
+            let queries = queries.into_iter().chain([0, u64::MAX]).collect();
+            test_nearest_impl(keys, queries);
+        }
+
+        #[test]
+        fn test_min_max(keys in vec(bits::u64::between(0, 10), 0..100)) {
+            test_min_max_impl(keys);
+        }
+
+        #[test]
+        fn test_min_max_full_width(keys in vec(any::<u64>(), 0..100)) {
+            test_min_max_impl(keys);
+        }
+
+        #[test]
+        fn test_range(
+            keys in vec(bits::u64::between(0, 10), 0..100),
+            bounds in (bits::u64::between(0, 11), bits::u64::between(0, 11)),
+        ) {
+            test_range_impl(keys, bounds);
+        }
+
+        #[test]
+        fn test_range_full_width(keys in vec(any::<u64>(), 0..100), bounds in any::<(u64, u64)>()) {
+            test_range_impl(keys, bounds);
+        }
+
+        #[test]
+        fn test_retain_matches_btree_map(
+            keys in vec(bits::u64::between(0, 10), 0..100),
+            modulus in 1..8u64,
+        ) {
+            test_retain_impl(keys, modulus);
+        }
+
+        #[test]
+        fn test_remove_prefix_against_retain(
+            keys in vec(bits::u64::between(0, 10), 0..100),
+            prefix in bits::u64::between(0, 10),
+            prefix_len in 0u8..12,
+        ) {
+            let (mut tree, mut reference) = from_keys(keys);
+            let mask = (1u64 << prefix_len) - 1;
+            let len = reference.len();
+            reference.retain(|key, _| key & mask != prefix & mask);
+            prop_assert_eq!(tree.remove_prefix(prefix, prefix_len), len - reference.len());
+            prop_assert_eq!(tree.len(), reference.len());
+            prop_assert!(tree.iter().eq(reference.iter().map(|(&k, v)| (k, v))));
+        }
+
+        #[test]
+        fn test_u32_keys(keys in vec(any::<u32>(), 0..100), removed in vec(any::<u32>(), 0..20)) {
+            let mut map = PatriciaTreeMap::<u32, u32>::default();
+            let mut reference = BTreeMap::new();
+            for key in keys {
+                assert_eq!(map.insert(key, key), reference.insert(key, key));
+            }
+            for key in removed {
+                assert_eq!(map.remove(key), reference.remove(&key));
+            }
+            assert_eq!(map.len(), reference.len());
+            assert!(map.iter().eq(reference.iter().map(|(&k, v)| (k, v))));
+        }
+
+        #[test]
+        #[cfg(debug_assertions)]
+        fn test_check_invariants(
+            operations in vec((0..3, bits::u64::between(0, 12)), 0..200),
+        ) {
+            let mut map = PatriciaTreeMap::new();
+            for (operation, key) in operations {
+                match operation {
+                    0 | 1 => {
+                        map.insert(key, key);
+                    }
+                    _ => {
+                        map.remove(key);
+                    }
+                }
+                map.check_invariants();
+            }
+            map.retain(|key, _| key % 3 == 0);
+            map.check_invariants();
+            PatriciaTreeMap::from_sorted_iter(map.into_iter()).check_invariants();
+        }
+
+        #[test]
+        fn test_operations_match_hash_map(
+            keys in vec(prop_oneof![any::<u64>(), any::<u64>().prop_map(|key| key << 48)], 1..32),
+            operations in vec(operation(), 0..300),
+        ) {
+            let mut map = PatriciaTreeMap::new();
+            let mut reference = HashMap::new();
+            for operation in operations {
+                match operation {
+                    Operation::Insert(key, value) => {
+                        let key = *key.get(&keys);
+                        assert_eq!(map.insert(key, value), reference.insert(key, value));
+                    }
+                    Operation::Remove(key) => {
+                        let key = *key.get(&keys);
+                        assert_eq!(map.remove(key), reference.remove(&key));
+                    }
+                    Operation::Get(key) => {
+                        let key = *key.get(&keys);
+                        assert_eq!(map.get(key), reference.get(&key));
+                    }
+                    Operation::Contains(key) => {
+                        let key = *key.get(&keys);
+                        assert_eq!(map.contains_key(key), reference.contains_key(&key));
+                    }
+                }
+                assert_eq!(map.len(), reference.len());
+                #[cfg(debug_assertions)]
+                map.check_invariants();
+            }
+        }
+
+        #[test]
+        fn test_split_off(keys in vec(bits::u64::between(0, 10), 0..100), key in bits::u64::between(0, 10)) {
+            let (mut tree, mut reference) = from_keys(keys);
+            let other = tree.split_off(key);
+            let reference_other = reference.split_off(&key);
+            assert_eq!(tree.len(), reference.len());
+            assert_eq!(other.len(), reference_other.len());
+            assert!(tree.into_iter().eq(reference));
+            assert!(other.into_iter().eq(reference_other));
+        }
+
+        #[test]
+        fn test_split_off_full_width(keys in vec(any::<u64>(), 0..100), key in any::<u64>()) {
+            let (mut tree, mut reference) = from_keys(keys);
+            let other = tree.split_off(key);
+            let reference_other = reference.split_off(&key);
+            assert!(tree.into_iter().eq(reference));
+            assert!(other.into_iter().eq(reference_other));
+        }
+
+        #[test]
+        fn test_rank_select(keys in vec(bits::u64::between(0, 10), 0..100), key in bits::u64::between(0, 10)) {
+            test_rank_select_impl(keys, key);
+        }
+
+        #[test]
+        fn test_rank_select_full_width(keys in vec(any::<u64>(), 0..100), key in any::<u64>()) {
+            test_rank_select_impl(keys, key);
+        }
+
+        #[test]
+        fn test_flat_round_trip(keys in vec(full_width_key(), 0..100)) {
+            let (tree, _) = from_keys(keys);
+            let flat = tree.to_flat();
+            prop_assert_eq!(flat.is_leaf.len(), (2 * tree.len()).saturating_sub(1));
+            let rebuilt = PatriciaTreeMap::from_flat(flat);
+            prop_assert_eq!(rebuilt.len(), tree.len());
+            prop_assert_eq!(format!("{:?}", rebuilt.debug_tree()), format!("{:?}", tree.debug_tree()));
+            prop_assert_eq!(rebuilt, tree);
+        }
+
+        #[test]
+        fn test_from_sorted_iter(mut entries in vec((bits::u64::between(0, 10), any::<u32>()), 0..100)) {
+            entries.sort_by_key(|(key, _)| *key);
+            let mut expected = PatriciaTreeMap::new();
+            for &(key, value) in entries.iter() {
+                expected.insert(key, value);
+            }
+            let map = PatriciaTreeMap::from_sorted_iter(entries);
+            assert_eq!(map.len(), expected.len());
+            // The shape of the tree only depends on its keys.
+            assert_eq!(format!("{:?}", map.debug_tree()), format!("{:?}", expected.debug_tree()));
+        }
+
+        #[test]
+        fn test_iter_impl_with_duplicates(keys in vec(bits::u64::between(0, 10), 0..100)) {
+            test_iter_impl(keys);
+        }
+
         #[test]
         fn test_iter_impl_unique(keys in unique_vec(bits::u64::between(0, 10), 0..100)) {
             test_iter_impl(keys);
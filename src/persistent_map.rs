@@ -0,0 +1,595 @@
+use crate::cursor::{Cursor, Tree};
+use crate::key::PatriciaKey;
+use alloc::vec::Vec;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
+use duplicate::duplicate;
+
+duplicate! {
+  [
+    module ptr   ptr_path            name                  iter_name  sharing_doc;
+    [rc]   [Rc]  [alloc::rc::Rc]     [RcPatriciaTreeMap]   [RcIter]   [
+      #[doc = ""]
+      #[doc = "Versions can only be used from the thread that created them; see"]
+      #[doc = "[`ArcPatriciaTreeMap`] for a thread-safe alternative."]
+    ];
+    [arc]  [Arc] [alloc::sync::Arc]  [ArcPatriciaTreeMap]  [ArcIter]  [
+      #[doc = ""]
+      #[doc = "As the nodes are shared through [`Arc`], versions can be sent to other threads and"]
+      #[doc = "read from several threads at once."]
+    ];
+  ]
+    mod module {
+        use super::*;
+        use ptr_path;
+
+        #[derive(Debug)]
+        struct LeafNode<V, K> {
+            key: K,
+            value: V,
+        }
+
+        #[derive(Debug)]
+        struct InternalNode<V, K> {
+            key_prefix: K,
+            branch_bit: u8,
+            left: ptr<Node<V, K>>,
+            right: ptr<Node<V, K>>,
+        }
+
+        #[derive(Debug)]
+        enum Node<V, K> {
+            Leaf(LeafNode<V, K>),
+            Internal(InternalNode<V, K>),
+        }
+
+        impl<V, K: PatriciaKey> Node<V, K> {
+            /// Returns the low bits shared by all keys below the node and their number.
+            fn prefix(&self) -> (K, u32) {
+                match self {
+                    Node::Leaf(LeafNode { key, .. }) => (*key, K::BITS),
+                    Node::Internal(InternalNode {
+                        key_prefix,
+                        branch_bit,
+                        ..
+                    }) => (*key_prefix, *branch_bit as u32),
+                }
+            }
+        }
+
+        /// A persistent variant of [`PatriciaTreeMap`] whose nodes are shared through reference
+        /// counting.
+        ///
+        /// Updates leave the map untouched and return a new version instead, which copies only the
+        /// nodes on the path to the updated key and shares all other subtrees with the old version.
+        /// Cloning a map is `O(1)`.
+        sharing_doc
+        ///
+        /// [`PatriciaTreeMap`]: crate::map::PatriciaTreeMap
+        #[derive(Debug)]
+        pub struct name<V, K = u64> {
+            size: usize,
+            root: Option<ptr<Node<V, K>>>,
+        }
+
+        impl<V> name<V> {
+            /// Creates an empty map with `u64` keys. Maps with other key types are created with
+            /// [`Default::default`].
+            pub fn new() -> Self {
+                Self::default()
+            }
+        }
+
+        impl<V, K: PatriciaKey> name<V, K> {
+            pub fn len(&self) -> usize {
+                self.size
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+
+            fn get_prefix(key: K, branch_bit: u8) -> K {
+                key.low_bits(branch_bit as u32)
+            }
+
+            fn is_left(key: K, branch_bit: u8) -> bool {
+                !key.bit(branch_bit as u32)
+            }
+
+            /// Returns whether both maps share the same root, so that they are the same version
+            /// or one is a clone of the other.
+            pub fn ptr_eq(&self, other: &Self) -> bool {
+                match (&self.root, &other.root) {
+                    (Some(a), Some(b)) => ptr::ptr_eq(a, b),
+                    (a, b) => a.is_none() && b.is_none(),
+                }
+            }
+
+            /// Returns the entries that differ between `self` and `other` in ascending key order,
+            /// as changes that turn `self` into `other`. Subtrees that both versions share are
+            /// skipped without being visited.
+            ///
+            /// The changes are collected eagerly: both trees are walked in tree order, which is not
+            /// key order, and the result is then sorted.
+            pub fn diff<'a>(&'a self, other: &'a Self) -> Vec<(K, Change<&'a V>)>
+            where
+                V: PartialEq,
+            {
+                /// Appends the entries of the subtree at `node` to `changes` as `change`.
+                fn all<'a, V, K: PatriciaKey>(
+                    node: &'a Node<V, K>,
+                    change: fn(&'a V) -> Change<&'a V>,
+                    changes: &mut Vec<(K, Change<&'a V>)>,
+                ) {
+                    match node {
+                        Node::Leaf(LeafNode { key, value }) => changes.push((*key, change(value))),
+                        Node::Internal(InternalNode { left, right, .. }) => {
+                            all(left, change, changes);
+                            all(right, change, changes);
+                        }
+                    }
+                }
+
+                fn aux<'a, V: PartialEq, K: PatriciaKey>(
+                    a: Option<&'a ptr<Node<V, K>>>,
+                    b: Option<&'a ptr<Node<V, K>>>,
+                    changes: &mut Vec<(K, Change<&'a V>)>,
+                ) {
+                    let (a, b) = match (a, b) {
+                        (Some(a), Some(b)) if ptr::ptr_eq(a, b) => return,
+                        (Some(a), Some(b)) => (a, b),
+                        (Some(a), None) => return all(a, Change::Removed, changes),
+                        (None, Some(b)) => return all(b, Change::Added, changes),
+                        (None, None) => return,
+                    };
+                    let ((p, m), (q, n)) = (a.prefix(), b.prefix());
+                    match (&**a, &**b) {
+                        (Node::Leaf(a), Node::Leaf(b)) if a.key == b.key => {
+                            if a.value != b.value {
+                                changes.push((a.key, Change::Modified(&a.value, &b.value)));
+                            }
+                        }
+                        (Node::Internal(a), Node::Internal(b)) if (p, m) == (q, n) => {
+                            aux(Some(&a.left), Some(&b.left), changes);
+                            aux(Some(&a.right), Some(&b.right), changes);
+                        }
+                        // All keys of `a` belong below one child of `b`.
+                        (_, Node::Internal(InternalNode { left, right, .. }))
+                            if n < m && name::<V, K>::get_prefix(p, n as u8) == q =>
+                        {
+                            if name::<V, K>::is_left(p, n as u8) {
+                                aux(Some(a), Some(left), changes);
+                                all(right, Change::Added, changes);
+                            } else {
+                                all(left, Change::Added, changes);
+                                aux(Some(a), Some(right), changes);
+                            }
+                        }
+                        // All keys of `b` belong below one child of `a`.
+                        (Node::Internal(InternalNode { left, right, .. }), _)
+                            if m < n && name::<V, K>::get_prefix(q, m as u8) == p =>
+                        {
+                            if name::<V, K>::is_left(q, m as u8) {
+                                aux(Some(left), Some(b), changes);
+                                all(right, Change::Removed, changes);
+                            } else {
+                                all(left, Change::Removed, changes);
+                                aux(Some(right), Some(b), changes);
+                            }
+                        }
+                        _ => {
+                            all(a, Change::Removed, changes);
+                            all(b, Change::Added, changes);
+                        }
+                    }
+                }
+
+                let mut changes = Vec::new();
+                aux(self.root.as_ref(), other.root.as_ref(), &mut changes);
+                changes.sort_unstable_by_key(|(key, _)| *key);
+                changes
+            }
+
+            pub fn get(&self, key: K) -> Option<&V> {
+                let mut node = self.root.as_deref()?;
+                loop {
+                    match node {
+                        Node::Internal(InternalNode {
+                            key_prefix,
+                            branch_bit,
+                            left,
+                            right,
+                        }) if *key_prefix == Self::get_prefix(key, *branch_bit) => {
+                            node = if Self::is_left(key, *branch_bit) {
+                                left
+                            } else {
+                                right
+                            };
+                        }
+                        Node::Leaf(LeafNode { key: k, value }) if *k == key => return Some(value),
+                        _ => return None,
+                    }
+                }
+            }
+
+            pub fn contains_key(&self, key: K) -> bool {
+                self.get(key).is_some()
+            }
+
+            /// Returns a new version of the map with `key` mapped to `value`.
+            pub fn insert(&self, key: K, value: V) -> Self {
+                /// Returns the updated subtree and whether `key` was newly added.
+                fn aux<V, K: PatriciaKey>(
+                    node: &ptr<Node<V, K>>,
+                    key: K,
+                    value: V,
+                ) -> (ptr<Node<V, K>>, bool) {
+                    let other = match &**node {
+                        Node::Internal(InternalNode {
+                            key_prefix,
+                            branch_bit,
+                            left,
+                            right,
+                        }) if *key_prefix == name::<V, K>::get_prefix(key, *branch_bit) => {
+                            let (left, right, added) =
+                                if name::<V, K>::is_left(key, *branch_bit) {
+                                    let (left, added) = aux(left, key, value);
+                                    (left, right.clone(), added)
+                                } else {
+                                    let (right, added) = aux(right, key, value);
+                                    (left.clone(), right, added)
+                                };
+                            let node = Node::Internal(InternalNode {
+                                key_prefix: *key_prefix,
+                                branch_bit: *branch_bit,
+                                left,
+                                right,
+                            });
+                            return (ptr::new(node), added);
+                        }
+                        Node::Leaf(LeafNode { key: k, .. }) if *k == key => {
+                            return (ptr::new(Node::Leaf(LeafNode { key, value })), false);
+                        }
+                        Node::Leaf(LeafNode { key: k, .. }) => *k,
+                        Node::Internal(InternalNode { key_prefix, .. }) => *key_prefix,
+                    };
+
+                    let branch_bit = other.branch_bit(key) as u8;
+                    let leaf = ptr::new(Node::Leaf(LeafNode { key, value }));
+                    let (left, right) = if name::<V, K>::is_left(key, branch_bit) {
+                        (leaf, node.clone())
+                    } else {
+                        (node.clone(), leaf)
+                    };
+                    let node = Node::Internal(InternalNode {
+                        key_prefix: name::<V, K>::get_prefix(key, branch_bit),
+                        branch_bit,
+                        left,
+                        right,
+                    });
+                    (ptr::new(node), true)
+                }
+
+                match &self.root {
+                    None => Self {
+                        size: 1,
+                        root: Some(ptr::new(Node::Leaf(LeafNode { key, value }))),
+                    },
+                    Some(root) => {
+                        let (root, added) = aux(root, key, value);
+                        Self {
+                            size: self.size + added as usize,
+                            root: Some(root),
+                        }
+                    }
+                }
+            }
+
+            /// Returns a new version of the map without `key`.
+            pub fn remove(&self, key: K) -> Self {
+                /// Returns `None` if `key` is not in the subtree, and otherwise the subtree without it.
+                fn aux<V, K: PatriciaKey>(node: &ptr<Node<V, K>>, key: K) -> Option<Option<ptr<Node<V, K>>>> {
+                    match &**node {
+                        Node::Internal(InternalNode {
+                            key_prefix,
+                            branch_bit,
+                            left,
+                            right,
+                        }) if *key_prefix == name::<V, K>::get_prefix(key, *branch_bit) => {
+                            let is_left = name::<V, K>::is_left(key, *branch_bit);
+                            let (child, sibling) = if is_left {
+                                (left, right)
+                            } else {
+                                (right, left)
+                            };
+                            Some(Some(match aux(child, key)? {
+                                // The sibling takes the place of the node.
+                                None => sibling.clone(),
+                                Some(child) => {
+                                    let (left, right) = if is_left {
+                                        (child, sibling.clone())
+                                    } else {
+                                        (sibling.clone(), child)
+                                    };
+                                    ptr::new(Node::Internal(InternalNode {
+                                        key_prefix: *key_prefix,
+                                        branch_bit: *branch_bit,
+                                        left,
+                                        right,
+                                    }))
+                                }
+                            }))
+                        }
+                        Node::Leaf(LeafNode { key: k, .. }) if *k == key => Some(None),
+                        _ => None,
+                    }
+                }
+
+                match self.root.as_ref().and_then(|root| aux(root, key)) {
+                    None => self.clone(),
+                    Some(root) => Self {
+                        size: self.size - 1,
+                        root,
+                    },
+                }
+            }
+
+            /// Returns an iterator over the entries of the map in ascending key order.
+            pub fn iter(&self) -> iter_name<'_, V, K> {
+                let (root, len) = (self.root.as_deref(), self.len());
+                iter_name {
+                    cursor: Cursor::new(Nodes(PhantomData), root, (K::MIN, K::MAX), (len, len)),
+                }
+            }
+        }
+
+        /// The nodes of a map as seen by a [`Cursor`].
+        struct Nodes<'a, V, K>(PhantomData<&'a Node<V, K>>);
+
+        impl<'a, V, K: PatriciaKey> Tree<K> for Nodes<'a, V, K> {
+            type Node = &'a Node<V, K>;
+            type Entry = (K, &'a V);
+
+            fn prefix(&self, node: &Self::Node) -> (K, u32) {
+                node.prefix()
+            }
+
+            fn open(&mut self, node: Self::Node) -> Result<Self::Entry, [Self::Node; 2]> {
+                match node {
+                    Node::Leaf(LeafNode { key, value }) => Ok((*key, value)),
+                    Node::Internal(InternalNode { left, right, .. }) => Err([left, right]),
+                }
+            }
+        }
+
+        /// An iterator over the entries of a map in ascending key order, created by
+        /// [`name::iter`].
+        pub struct iter_name<'a, V, K = u64> {
+            cursor: Cursor<Nodes<'a, V, K>, &'a Node<V, K>, K>,
+        }
+
+        impl<'a, V, K: PatriciaKey> Iterator for iter_name<'a, V, K> {
+            type Item = (K, &'a V);
+
+            fn next(&mut self) -> Option<Self::Item> {
+                self.cursor.next()
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                self.cursor.size_hint()
+            }
+        }
+
+        impl<'a, V, K: PatriciaKey> DoubleEndedIterator for iter_name<'a, V, K> {
+            fn next_back(&mut self) -> Option<Self::Item> {
+                self.cursor.next_back()
+            }
+        }
+
+        impl<'a, V, K: PatriciaKey> ExactSizeIterator for iter_name<'a, V, K> {}
+
+        impl<'a, V, K: PatriciaKey> FusedIterator for iter_name<'a, V, K> {}
+
+        impl<'a, V, K: PatriciaKey> IntoIterator for &'a name<V, K> {
+            type Item = (K, &'a V);
+            type IntoIter = iter_name<'a, V, K>;
+
+            fn into_iter(self) -> Self::IntoIter {
+                self.iter()
+            }
+        }
+
+        impl<V, K> Clone for name<V, K> {
+            fn clone(&self) -> Self {
+                Self {
+                    size: self.size,
+                    root: self.root.clone(),
+                }
+            }
+        }
+
+        impl<V, K: PatriciaKey> Default for name<V, K> {
+            fn default() -> Self {
+                Self {
+                    size: 0,
+                    root: None,
+                }
+            }
+        }
+
+        impl<V, K: PatriciaKey> FromIterator<(K, V)> for name<V, K> {
+            fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+                iter.into_iter()
+                    .fold(Self::default(), |map, (key, value)| map.insert(key, value))
+            }
+        }
+
+        #[cfg(all(test, feature = "std"))]
+        mod test {
+            use super::{name, ptr, Change, InternalNode, Node};
+            use proptest::collection::vec;
+            use proptest::prelude::*;
+            use std::collections::BTreeMap;
+
+            #[test]
+            fn test_versions() {
+                let v1: name<&str> = [(1, "a"), (2, "b")].into_iter().collect();
+                let v2 = v1.insert(3, "c");
+                let v3 = v2.insert(1, "d").remove(2);
+
+                assert_eq!(v1.iter().collect::<Vec<_>>(), [(1, &"a"), (2, &"b")]);
+                assert_eq!(
+                    v2.iter().collect::<Vec<_>>(),
+                    [(1, &"a"), (2, &"b"), (3, &"c")]
+                );
+                assert_eq!(v3.iter().collect::<Vec<_>>(), [(1, &"d"), (3, &"c")]);
+                assert_eq!((v1.len(), v2.len(), v3.len()), (2, 3, 2));
+
+                let clone = v3.clone();
+                let v4 = clone.insert(4, "e");
+                assert!(!clone.contains_key(4));
+                assert_eq!(v4.get(4), Some(&"e"));
+                assert_eq!(v3.remove(5).len(), 2);
+            }
+
+            #[test]
+            fn test_sharing() {
+                // 0 and 2 are below the left child of the root, 1 and 3 below the right one.
+                let v1: name<()> = (0..4).map(|key| (key, ())).collect();
+                let v2 = v1.insert(3, ());
+                let children = |map: &name<()>| match map.root.as_deref() {
+                    Some(Node::Internal(InternalNode { left, right, .. })) => (left.clone(), right.clone()),
+                    _ => unreachable!(),
+                };
+                let ((left1, right1), (left2, right2)) = (children(&v1), children(&v2));
+                assert!(ptr::ptr_eq(&left1, &left2));
+                assert!(!ptr::ptr_eq(&right1, &right2));
+            }
+
+            #[test]
+            fn test_diff() {
+                let v1: name<u64> = (0..1000).map(|key| (key * 7, key)).collect();
+                let v2 = v1.insert(5, 0);
+                assert!(v1.ptr_eq(&v1.clone()));
+                assert!(!v1.ptr_eq(&v2));
+                assert!(name::<u64>::new().ptr_eq(&name::new()));
+                assert!(v1.diff(&v1.clone()).is_empty());
+                assert_eq!(v1.diff(&v2), [(5, Change::Added(&0))]);
+                assert_eq!(v2.diff(&v1), [(5, Change::Removed(&0))]);
+
+                let v3 = v2.insert(14, 100).insert(21, 3).remove(700);
+                assert_eq!(
+                    v2.diff(&v3),
+                    [(14, Change::Modified(&2, &100)), (700, Change::Removed(&100))]
+                );
+            }
+
+            proptest! {
+                #[test]
+                fn test_diff_against_btree_map(
+                    a in vec((0u64..64, 0u32..4), 0..50),
+                    operations in vec((any::<bool>(), 0u64..64, 0u32..4), 0..20),
+                ) {
+                    let v1: name<u32> = a.into_iter().collect();
+                    let v2 = operations.into_iter().fold(v1.clone(), |map, (insert, key, value)| {
+                        if insert {
+                            map.insert(key, value)
+                        } else {
+                            map.remove(key)
+                        }
+                    });
+                    let a: BTreeMap<_, _> = v1.iter().collect();
+                    let b: BTreeMap<_, _> = v2.iter().collect();
+                    let expected: Vec<_> = a
+                        .keys()
+                        .chain(b.keys())
+                        .collect::<std::collections::BTreeSet<_>>()
+                        .into_iter()
+                        .filter_map(|key| match (a.get(key), b.get(key)) {
+                            (Some(x), Some(y)) if x == y => None,
+                            (Some(x), Some(y)) => Some((*key, Change::Modified(*x, *y))),
+                            (Some(x), None) => Some((*key, Change::Removed(*x))),
+                            (None, Some(y)) => Some((*key, Change::Added(*y))),
+                            (None, None) => unreachable!(),
+                        })
+                        .collect();
+                    prop_assert_eq!(v1.diff(&v2), expected);
+                    // Versions built independently share nothing, but still compare by value.
+                    let rebuilt: name<u32> = v2.iter().map(|(k, v)| (k, *v)).collect();
+                    prop_assert!(rebuilt.diff(&v2).is_empty());
+                }
+
+                #[test]
+                fn test_against_btree_map(
+                    operations in vec((any::<bool>(), 0u64..64, any::<u32>()), 0..100),
+                ) {
+                    let mut versions = vec![(name::new(), BTreeMap::new())];
+                    for (insert, key, value) in operations {
+                        let (map, reference) = versions.last().unwrap();
+                        let mut reference = reference.clone();
+                        let map = if insert {
+                            reference.insert(key, value);
+                            map.insert(key, value)
+                        } else {
+                            reference.remove(&key);
+                            map.remove(key)
+                        };
+                        versions.push((map, reference));
+                    }
+                    for (map, reference) in &versions {
+                        prop_assert_eq!(map.len(), reference.len());
+                        prop_assert!(map.iter().eq(reference.iter().map(|(&k, v)| (k, v))));
+                    }
+                }
+            }
+        }
+    }
+
+    pub use module::{iter_name, name};
+}
+
+/// How an entry differs between two versions of a persistent map, as returned by
+/// [`RcPatriciaTreeMap::diff`] and [`ArcPatriciaTreeMap::diff`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change<V> {
+    /// The key is only in the newer version.
+    Added(V),
+    /// The key is only in the older version.
+    Removed(V),
+    /// The key has the first value in the older version and the second in the newer one.
+    Modified(V, V),
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::ArcPatriciaTreeMap;
+    use std::thread;
+
+    #[test]
+    fn test_snapshots_across_threads() {
+        let mut versions = vec![ArcPatriciaTreeMap::new()];
+        for key in 0..100 {
+            let version = versions.last().unwrap().insert(key, key * 2);
+            versions.push(version);
+        }
+
+        let handles: Vec<_> = versions
+            .into_iter()
+            .enumerate()
+            .map(|(i, version)| {
+                thread::spawn(move || {
+                    assert_eq!(version.len(), i);
+                    assert!(version
+                        .iter()
+                        .map(|(k, v)| (k, *v))
+                        .eq((0..i as u64).map(|k| (k, k * 2))));
+                    assert!(!version.contains_key(i as u64));
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::map::PatriciaTreeMap;
+use core::net::Ipv4Addr;
+
+/// A routing table mapping IPv4 prefixes to values, looked up by longest-prefix match.
+///
+/// Each route is stored in a [`PatriciaTreeMap`] under a key with two bits per bit of the prefix,
+/// starting from the most significant bit of the address: the bit itself, followed by a set bit
+/// marking that the prefix continues. The key of a route is then a prefix of the key of an
+/// address, in the sense of [`PatriciaTreeMap::longest_prefix_match`], exactly if the route
+/// contains the address, and longer routes have longer keys.
+#[derive(Clone, Debug)]
+pub struct Ipv4RoutingTable<V> {
+    routes: PatriciaTreeMap<V>,
+}
+
+impl<V> Ipv4RoutingTable<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn route_key(addr: Ipv4Addr, prefix_len: u8) -> u64 {
+        let addr = addr.to_bits();
+        (0..prefix_len as u32).fold(0, |key, i| {
+            let bit = (addr >> (31 - i) & 1) as u64;
+            key | (bit | 0b10) << (2 * i)
+        })
+    }
+
+    /// Adds a route for the addresses whose first `prefix_len` bits are equal to those of
+    /// `prefix`, replacing any route with the same prefix. The remaining bits of `prefix` are
+    /// ignored.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix_len` is greater than 32.
+    pub fn add_route(&mut self, prefix: Ipv4Addr, prefix_len: u8, value: V) {
+        assert!(prefix_len <= 32, "invalid prefix length {}", prefix_len);
+        self.routes
+            .insert(Self::route_key(prefix, prefix_len), value);
+    }
+
+    /// Returns the value of the most specific route containing `addr`.
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<&V> {
+        self.routes
+            .longest_prefix_match(Self::route_key(addr, 32))
+            .map(|(_, value)| value)
+    }
+}
+
+impl<V> Default for Ipv4RoutingTable<V> {
+    fn default() -> Self {
+        Self {
+            routes: PatriciaTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::Ipv4RoutingTable;
+    use core::net::Ipv4Addr;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_default_route() {
+        let mut table = Ipv4RoutingTable::new();
+        assert_eq!(table.lookup(Ipv4Addr::new(10, 0, 0, 1)), None);
+
+        table.add_route(Ipv4Addr::UNSPECIFIED, 0, "default");
+        table.add_route(Ipv4Addr::new(10, 0, 0, 0), 24, "10.0.0.0/24");
+        table.add_route(Ipv4Addr::new(10, 0, 1, 0), 24, "10.0.1.0/24");
+        table.add_route(Ipv4Addr::new(10, 0, 0, 128), 25, "10.0.0.128/25");
+        table.add_route(Ipv4Addr::new(10, 0, 0, 7), 32, "10.0.0.7/32");
+        assert_eq!(table.len(), 5);
+
+        let lookup = |a, b, c, d| table.lookup(Ipv4Addr::new(a, b, c, d)).copied();
+        assert_eq!(lookup(10, 0, 0, 1), Some("10.0.0.0/24"));
+        assert_eq!(lookup(10, 0, 0, 7), Some("10.0.0.7/32"));
+        assert_eq!(lookup(10, 0, 0, 200), Some("10.0.0.128/25"));
+        assert_eq!(lookup(10, 0, 1, 200), Some("10.0.1.0/24"));
+        assert_eq!(lookup(10, 0, 2, 1), Some("default"));
+        assert_eq!(lookup(192, 168, 0, 1), Some("default"));
+    }
+
+    #[test]
+    fn test_host_bits_ignored() {
+        let mut table = Ipv4RoutingTable::new();
+        table.add_route(Ipv4Addr::new(192, 168, 1, 1), 16, 1);
+        table.add_route(Ipv4Addr::new(192, 168, 0, 0), 16, 2);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.lookup(Ipv4Addr::new(192, 168, 255, 255)), Some(&2));
+        assert_eq!(table.lookup(Ipv4Addr::new(192, 169, 0, 0)), None);
+    }
+
+    proptest! {
+        #[test]
+        fn test_lookup(
+            routes in vec((any::<u32>(), 0u8..=32), 0..50),
+            addrs in vec(any::<u32>(), 0..20),
+        ) {
+            let mut table = Ipv4RoutingTable::new();
+            for &(prefix, prefix_len) in &routes {
+                table.add_route(Ipv4Addr::from_bits(prefix), prefix_len, (prefix, prefix_len));
+            }
+            let contains = |prefix: u32, prefix_len: u8, addr: u32| {
+                prefix_len == 0 || (prefix ^ addr) >> (32 - prefix_len) == 0
+            };
+            let addrs = addrs.into_iter().chain(routes.iter().map(|&(prefix, _)| prefix));
+            for addr in addrs {
+                // The last route added with the longest matching length wins.
+                let expected = routes
+                    .iter()
+                    .rev()
+                    .filter(|&&(prefix, prefix_len)| contains(prefix, prefix_len, addr))
+                    .max_by_key(|&&(_, prefix_len)| prefix_len);
+                let actual = table.lookup(Ipv4Addr::from_bits(addr));
+                prop_assert_eq!(
+                    actual.map(|&(_, prefix_len)| prefix_len),
+                    expected.map(|&(_, prefix_len)| prefix_len)
+                );
+                if let (Some(&(prefix, prefix_len)), Some(expected)) = (actual, expected) {
+                    prop_assert!(contains(prefix, prefix_len, addr));
+                    prop_assert!(contains(expected.0, prefix_len, prefix));
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,13 @@
-use crate::map::PatriciaTreeMap;
+use crate::map::{Keys, PatriciaTreeMap};
+use core::fmt::{self, Debug};
+use core::iter::FusedIterator;
 
-#[derive(Debug)]
+/// A set of `u64` keys, stored in a Patricia tree.
+///
+/// Iteration, like the [`Debug`] output, is always in ascending key order. It only depends on
+/// the keys in the set, not on the order in which they were inserted, so it is the same across
+/// runs.
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct PatriciaTreeSet {
     base: PatriciaTreeMap<()>,
 }
@@ -21,12 +28,73 @@ impl PatriciaTreeSet {
     }
 
     pub fn contains(&self, key: u64) -> bool {
-        self.base.contains(key)
+        self.base.contains_key(key)
     }
 
     pub fn insert(&mut self, key: u64) -> bool {
         self.base.insert(key, ()).is_none()
     }
+
+    pub fn remove(&mut self, key: u64) -> bool {
+        self.base.remove(key).is_some()
+    }
+
+    /// Returns a set with the keys that are in `self` or `other`.
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            base: self.base.union(&other.base),
+        }
+    }
+
+    /// Returns a set with the keys that are in both `self` and `other`.
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self {
+            base: self.base.intersection(&other.base),
+        }
+    }
+
+    /// Returns a set with the keys that are in `self` but not in `other`.
+    pub fn difference(&self, other: &Self) -> Self {
+        Self {
+            base: self.base.difference(&other.base),
+        }
+    }
+
+    /// Returns a set with the keys that are in exactly one of `self` and `other`.
+    pub fn symmetric_difference(&self, other: &Self) -> Self {
+        Self {
+            base: self.base.symmetric_difference(&other.base),
+        }
+    }
+
+    /// Returns whether every key of `self` is also in `other`.
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.base.is_subset(&other.base)
+    }
+
+    /// Returns whether every key of `other` is also in `self`.
+    pub fn is_superset(&self, other: &Self) -> bool {
+        other.is_subset(self)
+    }
+
+    /// Returns whether `self` and `other` have no keys in common.
+    pub fn is_disjoint(&self, other: &Self) -> bool {
+        self.base.is_disjoint(&other.base)
+    }
+
+    /// Returns an iterator over the keys of the set in ascending order.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            inner: self.base.keys(),
+        }
+    }
+}
+
+/// Formats the set like a set literal, with its keys in ascending order.
+impl Debug for PatriciaTreeSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
 }
 
 impl Default for PatriciaTreeSet {
@@ -34,3 +102,261 @@ impl Default for PatriciaTreeSet {
         Self::new()
     }
 }
+
+pub struct Iter<'a> {
+    inner: Keys<'a, ()>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+impl<'a> FusedIterator for Iter<'a> {}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::PatriciaTreeSet;
+    use proptest::bits;
+    use proptest::collection::hash_set;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+
+    fn from_keys(keys: &[u64]) -> (PatriciaTreeSet, HashSet<u64>) {
+        let mut set = PatriciaTreeSet::new();
+        let mut reference = HashSet::new();
+        for &key in keys {
+            assert_eq!(set.insert(key), reference.insert(key));
+        }
+        (set, reference)
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut set = PatriciaTreeSet::new();
+        assert!(set.insert(3));
+        assert!(!set.insert(3));
+        assert!(set.insert(1));
+        assert_eq!(set.len(), 2);
+        assert!(set.remove(3));
+        assert!(!set.remove(3));
+        assert!(!set.contains(3));
+        assert!(set.contains(1));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_relations() {
+        let empty = PatriciaTreeSet::new();
+        let (a, _) = from_keys(&[1, 2, 3, 4, 1 << 40]);
+        let (b, _) = from_keys(&[2, 4]);
+        let (c, _) = from_keys(&[4, 5, 6]);
+        let (d, _) = from_keys(&[5, 6, 1 << 41]);
+
+        assert!(empty.is_subset(&empty));
+        assert!(empty.is_superset(&empty));
+        assert!(empty.is_disjoint(&empty));
+        assert!(empty.is_subset(&a));
+        assert!(!a.is_subset(&empty));
+        assert!(a.is_superset(&empty));
+        assert!(a.is_disjoint(&empty));
+
+        assert!(a.is_subset(&a.clone()));
+        assert!(a.is_superset(&a.clone()));
+        assert!(!a.is_disjoint(&a.clone()));
+
+        assert!(b.is_subset(&a));
+        assert!(!a.is_subset(&b));
+        assert!(a.is_superset(&b));
+        assert!(!c.is_subset(&a));
+        assert!(!a.is_superset(&c));
+        assert!(!a.is_disjoint(&c));
+        assert!(a.is_disjoint(&d));
+        assert!(!c.is_disjoint(&d));
+    }
+
+    #[test]
+    fn test_snapshot_output() {
+        let keys = [1 << 63, 5, 0, 1 << 40, 6, 3];
+        let expected = "{0, 3, 5, 6, 1099511627776, 9223372036854775808}";
+        let mut reversed = keys;
+        reversed.reverse();
+        for set in [from_keys(&keys).0, from_keys(&reversed).0] {
+            assert_eq!(
+                set.iter().collect::<Vec<_>>(),
+                [0, 3, 5, 6, 1 << 40, 1 << 63]
+            );
+            assert_eq!(format!("{:?}", set), expected);
+        }
+    }
+
+    #[test]
+    fn test_patricia_set_macro() {
+        assert_eq!(crate::patricia_set![], PatriciaTreeSet::new());
+        let set = crate::patricia_set![3, 1, 1 << 40, 3,];
+        assert_eq!(set.iter().collect::<Vec<_>>(), [1, 3, 1 << 40]);
+        assert_eq!(set, from_keys(&[1, 3, 1 << 40]).0);
+    }
+
+    fn test_membership_impl(keys: Vec<u64>) {
+        let (mut set, mut reference) = from_keys(&keys);
+        assert_eq!(set.len(), reference.len());
+
+        let mut sorted: Vec<_> = reference.iter().copied().collect();
+        sorted.sort_unstable();
+        assert_eq!(set.iter().collect::<Vec<_>>(), sorted);
+
+        for key in keys.iter().step_by(2) {
+            assert_eq!(set.remove(*key), reference.remove(key));
+        }
+        for key in 0..1 << 10 {
+            assert_eq!(set.contains(key), reference.contains(&key));
+        }
+    }
+
+    fn test_set_operation_impl(
+        a: Vec<u64>,
+        b: Vec<u64>,
+        operation: impl Fn(&PatriciaTreeSet, &PatriciaTreeSet) -> PatriciaTreeSet,
+        reference_operation: impl Fn(&HashSet<u64>, &HashSet<u64>) -> HashSet<u64>,
+    ) {
+        let (a, a_reference) = from_keys(&a);
+        let (b, b_reference) = from_keys(&b);
+
+        let res = operation(&a, &b);
+        let mut expected: Vec<_> = reference_operation(&a_reference, &b_reference)
+            .into_iter()
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(res.len(), expected.len());
+        assert_eq!(res.iter().collect::<Vec<_>>(), expected);
+        for key in expected {
+            assert!(res.contains(key));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_union(
+            a in vec(bits::u64::between(0, 10), 0..100),
+            b in vec(bits::u64::between(0, 10), 0..100),
+        ) {
+            test_set_operation_impl(a, b, PatriciaTreeSet::union, |a, b| a | b);
+        }
+
+        #[test]
+        fn test_union_full_width(a in vec(any::<u64>(), 0..100), b in vec(any::<u64>(), 0..100)) {
+            let b = b.into_iter().chain(a.iter().copied().step_by(2)).collect();
+            test_set_operation_impl(a, b, PatriciaTreeSet::union, |a, b| a | b);
+        }
+
+        #[test]
+        fn test_intersection(
+            a in vec(bits::u64::between(0, 10), 0..100),
+            b in vec(bits::u64::between(0, 10), 0..100),
+        ) {
+            test_set_operation_impl(a, b, PatriciaTreeSet::intersection, |a, b| a & b);
+        }
+
+        #[test]
+        fn test_intersection_full_width(
+            a in vec(any::<u64>(), 0..100),
+            b in vec(any::<u64>(), 0..100),
+        ) {
+            let b = b.into_iter().chain(a.iter().copied().step_by(2)).collect();
+            test_set_operation_impl(a, b, PatriciaTreeSet::intersection, |a, b| a & b);
+        }
+
+        #[test]
+        fn test_difference(
+            a in vec(bits::u64::between(0, 10), 0..100),
+            b in vec(bits::u64::between(0, 10), 0..100),
+        ) {
+            test_set_operation_impl(a, b, PatriciaTreeSet::difference, |a, b| a - b);
+        }
+
+        #[test]
+        fn test_difference_full_width(
+            a in vec(any::<u64>(), 0..100),
+            b in vec(any::<u64>(), 0..100),
+        ) {
+            let b = b.into_iter().chain(a.iter().copied().step_by(2)).collect();
+            test_set_operation_impl(a, b, PatriciaTreeSet::difference, |a, b| a - b);
+        }
+
+        #[test]
+        fn test_symmetric_difference(
+            a in vec(bits::u64::between(0, 10), 0..100),
+            b in vec(bits::u64::between(0, 10), 0..100),
+        ) {
+            test_set_operation_impl(a, b, PatriciaTreeSet::symmetric_difference, |a, b| a ^ b);
+        }
+
+        #[test]
+        fn test_symmetric_difference_full_width(
+            a in vec(any::<u64>(), 0..100),
+            b in vec(any::<u64>(), 0..100),
+        ) {
+            let b = b.into_iter().chain(a.iter().copied().step_by(2)).collect();
+            test_set_operation_impl(a, b, PatriciaTreeSet::symmetric_difference, |a, b| a ^ b);
+        }
+
+        #[test]
+        fn test_relations_match_hash_set(
+            a in vec(bits::u64::between(0, 6), 0..20),
+            b in vec(bits::u64::between(0, 6), 0..40),
+        ) {
+            let (a, a_reference) = from_keys(&a);
+            let (b, b_reference) = from_keys(&b);
+            prop_assert_eq!(a.is_subset(&b), a_reference.is_subset(&b_reference));
+            prop_assert_eq!(a.is_superset(&b), a_reference.is_superset(&b_reference));
+            prop_assert_eq!(a.is_disjoint(&b), a_reference.is_disjoint(&b_reference));
+        }
+
+        #[test]
+        fn test_deterministic_order(
+            (keys, shuffled) in vec(any::<u64>(), 0..100)
+                .prop_flat_map(|keys| (Just(keys.clone()), Just(keys).prop_shuffle())),
+            removed in vec(any::<u64>(), 0..10),
+        ) {
+            let mut sorted = keys.clone();
+            sorted.sort_unstable();
+            sorted.dedup();
+            let (a, _) = from_keys(&keys);
+            // Keys that are inserted and removed again leave no trace.
+            let (mut b, _) = from_keys(&[&removed[..], &shuffled[..]].concat());
+            for key in removed.into_iter().filter(|key| !keys.contains(key)) {
+                b.remove(key);
+            }
+            prop_assert_eq!(a.iter().collect::<Vec<_>>(), sorted.clone());
+            prop_assert_eq!(b.iter().collect::<Vec<_>>(), sorted);
+            prop_assert_eq!(format!("{:?}", a), format!("{:?}", b));
+        }
+
+        #[test]
+        fn test_membership_with_duplicates(keys in vec(bits::u64::between(0, 10), 0..100)) {
+            test_membership_impl(keys);
+        }
+
+        #[test]
+        fn test_membership_unique(keys in hash_set(bits::u64::between(0, 10), 0..100)) {
+            test_membership_impl(keys.into_iter().collect());
+        }
+    }
+}